@@ -0,0 +1,428 @@
+use crate::auth;
+use crate::domain::{Session, SessionStatus};
+use crate::timeline::{self, TimelineEntry};
+use crate::AppState;
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use std::collections::HashMap;
+use url::form_urlencoded;
+
+/// Requests under this prefix are served by the proxy itself and are never
+/// forwarded to the hub.
+pub const ADMIN_PREFIX: &str = "/soda/";
+
+/// Prometheus metrics, in the text exposition format.
+pub const METRICS_PATH: &str = "/metrics";
+
+pub fn is_an_admin_path(path: &str) -> bool {
+    path.starts_with(ADMIN_PREFIX) || path == METRICS_PATH
+}
+
+/// Serve the read-only admin API.
+/// - `GET /soda/sessions` : the live and recent sessions, which can be
+///   filtered with the `user`, `browser` and `status` query parameters
+/// - `GET /soda/sessions/{id}` : a single session
+/// - `GET /soda/sessions/{id}/timeline` : the last commands of a session, in
+///   JSON or in the HAR format with `format=har`, as a file with `download`
+/// - `GET /soda/hubs` : the hubs with their health and active sessions
+/// - `GET /soda/queue` : the new sessions waiting for a slot, by owner
+/// - `GET /metrics` : the Prometheus metrics
+///
+/// The clients must be authenticated like the WebDriver ones, when the
+/// authentication is enabled.
+pub async fn handle(req: &Request<Body>, state: &AppState) -> Response<Body> {
+    if let Err(message) = state.settings().auth.authenticate(req.headers()).await {
+        warn!(
+            "{} {} refused : {}",
+            req.method(),
+            req.uri().path(),
+            message
+        );
+        let mut response = json_error(StatusCode::UNAUTHORIZED, &message);
+        response.headers_mut().insert(
+            header::WWW_AUTHENTICATE,
+            header::HeaderValue::from_static(auth::CHALLENGE),
+        );
+        return response;
+    }
+
+    if req.method() != Method::GET {
+        return json_error(StatusCode::METHOD_NOT_ALLOWED, "Only GET is allowed");
+    }
+
+    if req.uri().path() == METRICS_PATH {
+        return Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Body::from(state.metrics.render(&state.sessions)))
+            .unwrap();
+    }
+
+    let segments: Vec<&str> = req
+        .uri()
+        .path()
+        .trim_start_matches(ADMIN_PREFIX)
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+
+    match segments.as_slice() {
+        ["sessions"] => {
+            let filter = SessionFilter::from_query(req.uri().query().unwrap_or(""));
+            match filter {
+                Ok(filter) => json(StatusCode::OK, &filter.apply(state.sessions.list())),
+                Err(err) => json_error(StatusCode::BAD_REQUEST, &err),
+            }
+        }
+        ["sessions", id] => match state.sessions.get(id) {
+            Some(session) => json(StatusCode::OK, &session),
+            None => json_error(StatusCode::NOT_FOUND, &format!("Unknown session {}", id)),
+        },
+        ["sessions", id, "timeline"] => timeline(id, req.uri().query().unwrap_or(""), state),
+        ["hubs"] => json(StatusCode::OK, &hubs(state)),
+        ["queue"] => json(StatusCode::OK, &state.queue.snapshot()),
+        _ => json_error(StatusCode::NOT_FOUND, "Unknown admin endpoint"),
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SessionTimeline {
+    session_id: String,
+    commands: Vec<TimelineEntry>,
+}
+
+/// The timeline of a known session, empty until its first command is done.
+fn timeline(id: &str, query: &str, state: &AppState) -> Response<Body> {
+    let commands = match (state.timelines.get(id), state.sessions.get(id)) {
+        (Some(commands), _) => commands,
+        (None, Some(_)) => vec![],
+        (None, None) => {
+            return json_error(StatusCode::NOT_FOUND, &format!("Unknown session {}", id))
+        }
+    };
+    let params: HashMap<String, String> = form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect();
+
+    let (mut response, file_name) = match params.get("format").map(String::as_str) {
+        Some("har") => (
+            json(StatusCode::OK, &timeline::to_har(id, &commands)),
+            format!("{}.har", id),
+        ),
+        Some("json") | None => (
+            json(
+                StatusCode::OK,
+                &SessionTimeline {
+                    session_id: id.to_string(),
+                    commands,
+                },
+            ),
+            format!("{}-timeline.json", id),
+        ),
+        Some(format) => {
+            return json_error(
+                StatusCode::BAD_REQUEST,
+                &format!("Unknown format {} (json or har)", format),
+            )
+        }
+    };
+
+    if params.contains_key("download") {
+        if let Ok(value) = format!("attachment; filename=\"{}\"", file_name).parse() {
+            response
+                .headers_mut()
+                .insert(header::CONTENT_DISPOSITION, value);
+        }
+    }
+    response
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HubStatus {
+    address: String,
+    weight: u32,
+    healthy: bool,
+    active_sessions: usize,
+}
+
+/// Every hub known by the router, once even when it belongs to several pools.
+fn hubs(state: &AppState) -> Vec<HubStatus> {
+    let active_sessions = state.sessions.active_sessions_by_hub();
+    let settings = state.settings();
+    let mut hubs: Vec<HubStatus> = Vec::new();
+
+    for hub in settings.router.hubs() {
+        if hubs.iter().any(|known| known.address == hub.address) {
+            continue;
+        }
+        hubs.push(HubStatus {
+            address: hub.address.to_owned(),
+            weight: hub.weight,
+            healthy: hub.is_healthy(),
+            active_sessions: active_sessions.get(&hub.address).copied().unwrap_or(0),
+        });
+    }
+
+    hubs
+}
+
+#[derive(Default)]
+struct SessionFilter {
+    user: Option<String>,
+    browser: Option<String>,
+    status: Option<SessionStatus>,
+}
+
+impl SessionFilter {
+    fn from_query(query: &str) -> Result<SessionFilter, String> {
+        let params: HashMap<String, String> = form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect();
+
+        let status = match params.get("status") {
+            Some(status) => Some(status.parse::<SessionStatus>()?),
+            None => None,
+        };
+
+        Ok(SessionFilter {
+            user: params.get("user").cloned(),
+            browser: params.get("browser").cloned(),
+            status,
+        })
+    }
+
+    fn matches(&self, session: &Session) -> bool {
+        if let Some(user) = &self.user {
+            if &session.owner != user {
+                return false;
+            }
+        }
+
+        if let Some(browser) = &self.browser {
+            match &session.capabilities.browser_name {
+                Some(name) if name.eq_ignore_ascii_case(browser) => {}
+                _ => return false,
+            }
+        }
+
+        match self.status {
+            Some(status) => session.status == status,
+            None => true,
+        }
+    }
+
+    /// Keep the matching sessions, the most recent first.
+    fn apply(&self, sessions: Vec<Session>) -> Vec<Session> {
+        let mut sessions: Vec<Session> = sessions
+            .into_iter()
+            .filter(|session| self.matches(session))
+            .collect();
+
+        sessions.sort_by_key(|session| std::cmp::Reverse(session.start_time));
+        sessions
+    }
+}
+
+fn json<T: serde::Serialize>(status: StatusCode, value: &T) -> Response<Body> {
+    let body = serde_json::to_vec(value).unwrap_or_default();
+
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn json_error(status: StatusCode, message: &str) -> Response<Body> {
+    json(status, &serde_json::json!({ "error": message }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{Authenticator, IdentityMode};
+    use crate::config::Settings;
+    use crate::domain::DesiredCapabilities;
+    use serde_json::Value;
+    use uuid::Uuid;
+
+    fn state_with_sessions() -> AppState {
+        let state = AppState::new("127.0.0.1:4444".to_string(), 60);
+
+        for (session_id, user, browser) in &[("1", "team-a", "chrome"), ("2", "team-b", "firefox")]
+        {
+            let request_id = Uuid::new_v4();
+            let capabilities = DesiredCapabilities {
+                browser_name: Some(browser.to_string()),
+                soda_user: Some(user.to_string()),
+                ..DesiredCapabilities::default()
+            };
+            state.sessions.queue(request_id, capabilities);
+            state
+                .sessions
+                .activate(request_id, session_id, DesiredCapabilities::default());
+        }
+        state.sessions.delete("2");
+
+        state
+    }
+
+    async fn get(state: &AppState, uri: &str) -> (StatusCode, Value) {
+        let req = Request::get(uri).body(Body::empty()).unwrap();
+        let response = handle(&req, state).await;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn handle_requires_the_authentication_when_enabled() {
+        let mut settings = Settings::new("127.0.0.1:4444".to_string(), 60);
+        let mut tokens = HashMap::new();
+        // SHA-256 of my-token
+        tokens.insert(
+            "fece50d2287f7245aea5819b75f95ee8bec295a14f8ef1e7a31f17f1dae9df44".to_string(),
+            "team-a".to_string(),
+        );
+        settings.auth = Authenticator::new(HashMap::new(), tokens, IdentityMode::Override);
+        let state = AppState::with_settings(settings);
+
+        let anonymous = Request::get("/soda/sessions").body(Body::empty()).unwrap();
+        let response = handle(&anonymous, &state).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.headers()[header::WWW_AUTHENTICATE],
+            auth::CHALLENGE
+        );
+
+        let authenticated = Request::get("/metrics")
+            .header(header::AUTHORIZATION, "Bearer my-token")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            handle(&authenticated, &state).await.status(),
+            StatusCode::OK
+        );
+    }
+
+    #[test]
+    fn is_an_admin_path_only_matches_the_reserved_prefix() {
+        assert!(is_an_admin_path("/soda/sessions"));
+        assert!(is_an_admin_path("/metrics"));
+        assert!(!is_an_admin_path("/wd/hub/session"));
+    }
+
+    #[tokio::test]
+    async fn metrics_exposes_the_active_sessions() {
+        let req = Request::get("/metrics").body(Body::empty()).unwrap();
+        let response = handle(&req, &state_with_sessions()).await;
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        assert!(std::str::from_utf8(&body)
+            .unwrap()
+            .contains("soda_active_sessions 1"));
+    }
+
+    #[tokio::test]
+    async fn sessions_returns_every_session() {
+        let (status, body) = get(&state_with_sessions(), "/soda/sessions").await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.as_array().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn sessions_filters_by_user_browser_and_status() {
+        let state = state_with_sessions();
+
+        let (_, by_user) = get(&state, "/soda/sessions?user=team-a").await;
+        let (_, by_browser) = get(&state, "/soda/sessions?browser=FIREFOX").await;
+        let (_, by_status) = get(&state, "/soda/sessions?status=active").await;
+
+        assert_eq!(by_user[0]["id"], "1");
+        assert_eq!(by_user.as_array().unwrap().len(), 1);
+        assert_eq!(by_browser[0]["id"], "2");
+        assert_eq!(by_browser.as_array().unwrap().len(), 1);
+        assert_eq!(by_status[0]["id"], "1");
+        assert_eq!(by_status.as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn sessions_returns_a_bad_request_when_the_status_is_unknown() {
+        let (status, _) = get(&state_with_sessions(), "/soda/sessions?status=sleeping").await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn session_returns_the_session_or_not_found() {
+        let state = state_with_sessions();
+
+        let (found, session) = get(&state, "/soda/sessions/2").await;
+        let (not_found, _) = get(&state, "/soda/sessions/3").await;
+
+        assert_eq!(found, StatusCode::OK);
+        assert_eq!(session["owner"], "team-b");
+        assert_eq!(session["status"], "deleted");
+        assert_eq!(not_found, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn hubs_returns_the_health_and_the_active_sessions_of_the_hubs() {
+        let state = state_with_sessions();
+        state.sessions.set_hub("1", "127.0.0.1:4444");
+
+        let (status, hubs) = get(&state, "/soda/hubs").await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(hubs.as_array().unwrap().len(), 1);
+        assert_eq!(hubs[0]["address"], "127.0.0.1:4444");
+        assert_eq!(hubs[0]["healthy"], true);
+        assert_eq!(hubs[0]["activeSessions"], 1);
+    }
+
+    #[tokio::test]
+    async fn timeline_returns_the_commands_of_the_session_in_json_or_har() {
+        let state = state_with_sessions();
+        let now = chrono::Utc::now();
+        state.timelines.record(
+            "1",
+            TimelineEntry {
+                request_id: Uuid::new_v4(),
+                command: "navigateTo".to_string(),
+                method: "POST".to_string(),
+                path: "/session/1/url".to_string(),
+                params: Some(serde_json::json!({"url": "https://example.com"})),
+                status: 200,
+                error: None,
+                started_at: now,
+                ended_at: now,
+                duration_ms: 12,
+                screenshot: None,
+            },
+            10,
+        );
+
+        let (_, timeline) = get(&state, "/soda/sessions/1/timeline").await;
+        let (_, har) = get(&state, "/soda/sessions/1/timeline?format=har").await;
+        let (empty, no_commands) = get(&state, "/soda/sessions/2/timeline").await;
+        let (not_found, _) = get(&state, "/soda/sessions/3/timeline").await;
+
+        assert_eq!(timeline["sessionId"], "1");
+        assert_eq!(timeline["commands"][0]["command"], "navigateTo");
+        assert_eq!(har["log"]["entries"][0]["request"]["method"], "POST");
+        assert_eq!(empty, StatusCode::OK);
+        assert_eq!(no_commands["commands"].as_array().unwrap().len(), 0);
+        assert_eq!(not_found, StatusCode::NOT_FOUND);
+
+        let req = Request::get("/soda/sessions/1/timeline?format=har&download")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            handle(&req, &state).await.headers()[header::CONTENT_DISPOSITION],
+            "attachment; filename=\"1.har\""
+        );
+    }
+}
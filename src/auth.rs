@@ -0,0 +1,352 @@
+use hyper::header::{HeaderMap, AUTHORIZATION};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// An `Authorization` header refused is refused again without being
+/// verified during this time, so that bcrypt isn't run on every retry.
+const REJECTION_TTL: Duration = Duration::from_secs(30);
+
+/// Headers remembered as refused at most, the expired ones are forgotten first.
+const MAX_REJECTIONS: usize = 10_000;
+
+/// An `Authorization` header verified is accepted without being verified
+/// again during this time.
+const VERIFICATION_TTL: Duration = Duration::from_secs(300);
+
+/// Headers remembered as verified at most, the expired ones are forgotten first.
+const MAX_VERIFICATIONS: usize = 10_000;
+
+/// The `WWW-Authenticate` challenge of the requests refused.
+pub const CHALLENGE: &str = "Basic realm=\"soda-test-service\"";
+
+/// How the authenticated user is reconciled with the `soda:user` capability.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IdentityMode {
+    /// `soda:user` is replaced by the authenticated user.
+    Override,
+    /// A new session with another `soda:user` than the authenticated user is refused.
+    Validate,
+}
+
+impl FromStr for IdentityMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "override" => Ok(IdentityMode::Override),
+            "validate" => Ok(IdentityMode::Validate),
+            _ => Err(format!("Unknown identity mode : {}", s)),
+        }
+    }
+}
+
+/// Authenticate the Selenium clients, with HTTP Basic against a credentials
+/// file or with a bearer token. Without any credentials nor tokens, every
+/// client is accepted as it is.
+pub struct Authenticator {
+    pub mode: IdentityMode,
+    /// bcrypt hash of the password, by user
+    passwords: HashMap<String, String>,
+    /// User, by SHA-256 hash (hex) of the token
+    tokens: HashMap<String, String>,
+    /// User and date, by SHA-256 hash of the `Authorization` headers already
+    /// verified, so that bcrypt isn't run on every WebDriver command. A reload
+    /// of the credentials or of the tokens starts with an empty cache.
+    verified: Mutex<HashMap<String, (String, Instant)>>,
+    /// Error and date, by SHA-256 hash of the `Authorization` headers refused.
+    rejected: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+impl Default for Authenticator {
+    fn default() -> Authenticator {
+        Authenticator::new(HashMap::new(), HashMap::new(), IdentityMode::Override)
+    }
+}
+
+impl Authenticator {
+    pub fn new(
+        passwords: HashMap<String, String>,
+        tokens: HashMap<String, String>,
+        mode: IdentityMode,
+    ) -> Authenticator {
+        Authenticator {
+            mode,
+            passwords,
+            tokens,
+            verified: Mutex::new(HashMap::new()),
+            rejected: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Read the credentials file (`USER:BCRYPT_HASH` lines, as written by
+    /// `htpasswd -B`) and the tokens file (`USER:SHA256_OF_THE_TOKEN` lines).
+    pub fn from_files(
+        credentials: Option<&str>,
+        tokens: Option<&str>,
+        mode: IdentityMode,
+    ) -> Result<Authenticator, String> {
+        let passwords = match credentials {
+            Some(path) => read_pairs(path)?,
+            None => HashMap::new(),
+        };
+        let tokens = match tokens {
+            Some(path) => read_pairs(path)?
+                .into_iter()
+                .map(|(user, hash)| (hash.to_lowercase(), user))
+                .collect(),
+            None => HashMap::new(),
+        };
+
+        Ok(Authenticator::new(passwords, tokens, mode))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.passwords.is_empty() || !self.tokens.is_empty()
+    }
+
+    /// The authenticated user, `None` when the authentication is disabled.
+    pub async fn authenticate(&self, headers: &HeaderMap) -> Result<Option<String>, String> {
+        if !self.is_enabled() {
+            return Ok(None);
+        }
+
+        let authorization = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| "Authentication required".to_string())?;
+
+        let key = sha256(authorization);
+        if let Some((user, verified_at)) = self.verified.lock().unwrap().get(&key) {
+            if verified_at.elapsed() < VERIFICATION_TTL {
+                return Ok(Some(user.to_owned()));
+            }
+        }
+        if let Some((err, rejected_at)) = self.rejected.lock().unwrap().get(&key) {
+            if rejected_at.elapsed() < REJECTION_TTL {
+                return Err(err.to_owned());
+            }
+        }
+
+        let verified = match authorization.split_once(' ') {
+            Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => {
+                self.verify_token(token.trim())
+            }
+            Some((scheme, credentials)) if scheme.eq_ignore_ascii_case("basic") => {
+                self.verify_password(credentials.trim()).await
+            }
+            _ => Err("Unsupported authentication scheme".to_string()),
+        };
+
+        match verified {
+            Ok(user) => {
+                remember(
+                    &self.verified,
+                    key,
+                    &user,
+                    VERIFICATION_TTL,
+                    MAX_VERIFICATIONS,
+                );
+                Ok(Some(user))
+            }
+            Err(err) => {
+                remember(&self.rejected, key, &err, REJECTION_TTL, MAX_REJECTIONS);
+                Err(err)
+            }
+        }
+    }
+
+    fn verify_token(&self, token: &str) -> Result<String, String> {
+        self.tokens
+            .get(&sha256(token))
+            .cloned()
+            .ok_or_else(|| "Invalid token".to_string())
+    }
+
+    /// bcrypt is slow on purpose : the password is verified on the blocking
+    /// threads, not on the threads serving the requests.
+    async fn verify_password(&self, credentials: &str) -> Result<String, String> {
+        let credentials = base64::decode(credentials)
+            .ok()
+            .and_then(|credentials| String::from_utf8(credentials).ok())
+            .ok_or_else(|| "Invalid basic credentials".to_string())?;
+        let (user, password) = credentials
+            .split_once(':')
+            .ok_or_else(|| "Invalid basic credentials".to_string())?;
+
+        let invalid = format!("Invalid password for the user {}", user);
+        let hash = self
+            .passwords
+            .get(user)
+            .cloned()
+            .ok_or_else(|| invalid.to_owned())?;
+        let password = password.to_string();
+        let is_valid = tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash))
+            .await
+            .map(|verified| verified.unwrap_or(false))
+            .unwrap_or(false);
+
+        match is_valid {
+            true => Ok(user.to_string()),
+            false => Err(invalid),
+        }
+    }
+}
+
+/// Remember the outcome of a verified header, the expired ones are forgotten
+/// when too many are remembered.
+fn remember(
+    cache: &Mutex<HashMap<String, (String, Instant)>>,
+    key: String,
+    value: &str,
+    ttl: Duration,
+    max: usize,
+) {
+    let mut cache = cache.lock().unwrap();
+    if cache.len() >= max {
+        cache.retain(|_, (_, remembered_at)| remembered_at.elapsed() < ttl);
+    }
+    if cache.len() >= max {
+        cache.clear();
+    }
+    cache.insert(key, (value.to_string(), Instant::now()));
+}
+
+fn sha256(value: &str) -> String {
+    Sha256::digest(value.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// `KEY:VALUE` lines, the empty lines and the `#` comments are ignored.
+fn read_pairs(path: &str) -> Result<HashMap<String, String>, String> {
+    let content = fs::read_to_string(path).map_err(|err| format!("{} : {}", path, err))?;
+
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| match line.split_once(':') {
+            Some((key, value)) => Ok((key.trim().to_string(), value.trim().to_string())),
+            None => Err(format!("{} : {} must be USER:HASH", path, line)),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn authenticator() -> Authenticator {
+        let mut passwords = HashMap::new();
+        passwords.insert("team-a".to_string(), bcrypt::hash("secret", 4).unwrap());
+        let mut tokens = HashMap::new();
+        tokens.insert(sha256("my-token"), "team-b".to_string());
+
+        Authenticator::new(passwords, tokens, IdentityMode::Override)
+    }
+
+    fn headers(authorization: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_str(authorization).unwrap());
+        headers
+    }
+
+    #[tokio::test]
+    async fn authenticate_accepts_everyone_when_disabled() {
+        assert_eq!(
+            Authenticator::default()
+                .authenticate(&HeaderMap::new())
+                .await,
+            Ok(None)
+        );
+    }
+
+    #[tokio::test]
+    async fn authenticate_verifies_the_basic_credentials() {
+        let authenticator = authenticator();
+        let valid = format!("Basic {}", base64::encode("team-a:secret"));
+        let invalid = format!("Basic {}", base64::encode("team-a:guess"));
+
+        assert_eq!(
+            authenticator.authenticate(&headers(&valid)).await,
+            Ok(Some("team-a".to_string()))
+        );
+        // Served by the cache the second time
+        assert_eq!(
+            authenticator.authenticate(&headers(&valid)).await,
+            Ok(Some("team-a".to_string()))
+        );
+        assert!(authenticator
+            .authenticate(&headers(&invalid))
+            .await
+            .is_err());
+        // Refused by the cache the second time
+        assert!(authenticator
+            .rejected
+            .lock()
+            .unwrap()
+            .contains_key(&sha256(&invalid)));
+        assert!(authenticator
+            .authenticate(&headers(&invalid))
+            .await
+            .is_err());
+        assert!(authenticator.authenticate(&HeaderMap::new()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn authenticate_verifies_the_bearer_token() {
+        let authenticator = authenticator();
+
+        assert_eq!(
+            authenticator
+                .authenticate(&headers("Bearer my-token"))
+                .await,
+            Ok(Some("team-b".to_string()))
+        );
+        assert!(authenticator
+            .authenticate(&headers("Bearer another-token"))
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn remember_forgets_the_expired_headers_when_full() {
+        let cache = Mutex::new(HashMap::new());
+        remember(&cache, "a".to_string(), "team-a", Duration::from_secs(0), 2);
+        remember(&cache, "b".to_string(), "team-b", Duration::from_secs(0), 2);
+        assert_eq!(cache.lock().unwrap().len(), 2);
+
+        remember(&cache, "c".to_string(), "team-c", Duration::from_secs(0), 2);
+        let cache = cache.lock().unwrap();
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache["c"].0, "team-c");
+    }
+
+    #[tokio::test]
+    async fn authenticate_forgets_the_verified_headers_on_reload() {
+        let file = std::env::temp_dir().join(format!("tokens-{}", uuid::Uuid::new_v4()));
+        let path = file.to_str().unwrap();
+        fs::write(&file, format!("team-b:{}\n", sha256("my-token"))).unwrap();
+        let authenticator = Authenticator::from_files(None, Some(path), IdentityMode::Override);
+        assert!(authenticator
+            .unwrap()
+            .authenticate(&headers("Bearer my-token"))
+            .await
+            .is_ok());
+
+        fs::write(&file, format!("team-b:{}\n", sha256("new-token"))).unwrap();
+        let reloaded = Authenticator::from_files(None, Some(path), IdentityMode::Override).unwrap();
+        assert!(reloaded
+            .authenticate(&headers("Bearer my-token"))
+            .await
+            .is_err());
+
+        fs::remove_file(&file).unwrap();
+    }
+}
@@ -0,0 +1,325 @@
+use crate::AppState;
+use reqwest::Client;
+use std::collections::HashMap;
+use std::net::ToSocketAddrs;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Timeout of the health check of a hub.
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Address of a hub with its weight, format : `IP:PORT[*WEIGHT]`
+#[derive(Clone, Debug, PartialEq)]
+pub struct HubAddress {
+    pub address: String,
+    pub weight: u32,
+}
+
+impl FromStr for HubAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, weight) = match s.split_once('*') {
+            Some((address, weight)) => {
+                let weight = weight
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|weight| *weight > 0)
+                    .ok_or_else(|| format!("Hub {} : the weight must be a positive integer", s))?;
+                (address, weight)
+            }
+            None => (s, 1),
+        };
+
+        if !address.contains(':') {
+            return Err(format!("Hub {} : format must be IP:PORT[*WEIGHT]", s));
+        }
+
+        Ok(HubAddress {
+            address: address.trim().to_string(),
+            weight,
+        })
+    }
+}
+
+impl HubAddress {
+    /// The hub with its resolved `IP:PORT`, so that a hub given by name and
+    /// by IP is the same hub everywhere.
+    pub fn resolve(&self) -> Result<HubAddress, String> {
+        let address = self
+            .address
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addresses| addresses.next())
+            .ok_or_else(|| format!("The hub {} can't be resolved", self.address))?;
+
+        Ok(HubAddress {
+            address: address.to_string(),
+            weight: self.weight,
+        })
+    }
+}
+
+/// How the new sessions are distributed between the hubs of a pool.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Balancing {
+    RoundRobin,
+    LeastSessions,
+    Weighted,
+}
+
+impl FromStr for Balancing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(Balancing::RoundRobin),
+            "least-sessions" => Ok(Balancing::LeastSessions),
+            "weighted" => Ok(Balancing::Weighted),
+            _ => Err(format!("Unknown balancing strategy : {}", s)),
+        }
+    }
+}
+
+pub struct Hub {
+    pub address: String,
+    pub weight: u32,
+    healthy: AtomicBool,
+}
+
+impl Hub {
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::SeqCst)
+    }
+
+    /// Take the health of the same hub in a previous configuration, until
+    /// the next health check.
+    pub fn keep_health_of(&self, previous: &Hub) {
+        self.healthy.store(previous.is_healthy(), Ordering::SeqCst);
+    }
+
+    fn set_healthy(&self, healthy: bool) {
+        if self.healthy.swap(healthy, Ordering::SeqCst) != healthy {
+            match healthy {
+                true => info!("The hub {} is back", self.address),
+                false => warn!(
+                    "The hub {} is unhealthy, no session will be sent to it",
+                    self.address
+                ),
+            }
+        }
+    }
+}
+
+/// Equivalent hubs sharing the new sessions.
+pub struct HubPool {
+    hubs: Vec<Hub>,
+    next: AtomicUsize,
+}
+
+impl HubPool {
+    pub fn new(addresses: Vec<HubAddress>) -> HubPool {
+        HubPool {
+            hubs: addresses
+                .into_iter()
+                .map(|address| Hub {
+                    address: address.address,
+                    weight: address.weight,
+                    healthy: AtomicBool::new(true),
+                })
+                .collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn hubs(&self) -> &[Hub] {
+        &self.hubs
+    }
+
+    /// Choose the hub of a new session among the healthy ones. When every
+    /// hub is unhealthy, all of them are candidates rather than failing.
+    pub fn pick(
+        &self,
+        balancing: Balancing,
+        active_sessions: &HashMap<String, usize>,
+    ) -> Option<&str> {
+        let healthy: Vec<&Hub> = self.hubs.iter().filter(|hub| hub.is_healthy()).collect();
+        let candidates: Vec<&Hub> = match healthy.is_empty() {
+            true => self.hubs.iter().collect(),
+            false => healthy,
+        };
+
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let next = self.next.fetch_add(1, Ordering::SeqCst);
+        let hub = match balancing {
+            Balancing::RoundRobin => candidates[next % candidates.len()],
+            Balancing::LeastSessions => candidates
+                .iter()
+                .min_by_key(|hub| active_sessions.get(&hub.address).copied().unwrap_or(0))
+                .copied()
+                .unwrap(),
+            Balancing::Weighted => {
+                let total: usize = candidates.iter().map(|hub| hub.weight as usize).sum();
+                let mut slot = next % total;
+                candidates
+                    .iter()
+                    .find(|hub| {
+                        if slot < hub.weight as usize {
+                            return true;
+                        }
+                        slot -= hub.weight as usize;
+                        false
+                    })
+                    .copied()
+                    .unwrap()
+            }
+        };
+
+        Some(&hub.address)
+    }
+}
+
+/// Periodically check the status endpoint of every hub, under the base path
+/// of the hubs (e.g. `/wd/hub/status`), the unhealthy ones
+/// don't receive new sessions until they are back.
+pub async fn run_health_checks(state: Arc<AppState>, interval: Duration) {
+    loop {
+        // The configuration may be reloaded, only its pools are checked
+        let settings = state.settings();
+        if !settings.router.has_a_pool() {
+            tokio::time::delay_for(interval).await;
+            continue;
+        }
+
+        // The same hub can belong to several pools, check it once
+        let mut hubs: HashMap<&str, Vec<&Hub>> = HashMap::new();
+        for hub in settings.router.hubs() {
+            hubs.entry(&hub.address).or_default().push(hub);
+        }
+
+        for (address, hubs) in hubs {
+            let healthy = is_healthy(&state.client, address, &settings.base_path).await;
+            for hub in hubs {
+                hub.set_healthy(healthy);
+            }
+        }
+
+        tokio::time::delay_for(interval).await;
+    }
+}
+
+/// A hub is healthy when its status endpoint answers and it doesn't
+/// claim not to be ready (W3C `value.ready`).
+async fn is_healthy(client: &Client, address: &str, base_path: &str) -> bool {
+    let url = format!("http://{}{}/status", address, base_path);
+
+    let response = match client.get(&url).timeout(HEALTH_CHECK_TIMEOUT).send().await {
+        Ok(response) if response.status().is_success() => response,
+        Ok(response) => {
+            debug!("Health check of {} : {}", address, response.status());
+            return false;
+        }
+        Err(err) => {
+            debug!("Health check of {} : {}", address, err);
+            return false;
+        }
+    };
+
+    let status = response
+        .bytes()
+        .await
+        .ok()
+        .and_then(|body| serde_json::from_slice::<serde_json::Value>(&body).ok());
+
+    status
+        .as_ref()
+        .and_then(|status| status.pointer("/value/ready"))
+        .and_then(serde_json::Value::as_bool)
+        .unwrap_or(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(addresses: &[&str]) -> HubPool {
+        HubPool::new(
+            addresses
+                .iter()
+                .map(|address| address.parse().unwrap())
+                .collect(),
+        )
+    }
+
+    fn picks(pool: &HubPool, balancing: Balancing, count: usize) -> Vec<String> {
+        (0..count)
+            .map(|_| pool.pick(balancing, &HashMap::new()).unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn hub_address_is_parsed_with_an_optional_weight() {
+        assert_eq!(
+            "hub:4444*3".parse::<HubAddress>(),
+            Ok(HubAddress {
+                address: "hub:4444".to_string(),
+                weight: 3
+            })
+        );
+        assert_eq!("hub:4444".parse::<HubAddress>().unwrap().weight, 1);
+        assert!("hub:4444*0".parse::<HubAddress>().is_err());
+        assert!("hub".parse::<HubAddress>().is_err());
+    }
+
+    #[test]
+    fn pick_rotates_the_hubs_with_round_robin() {
+        let pool = pool(&["a:4444", "b:4444"]);
+
+        assert_eq!(
+            picks(&pool, Balancing::RoundRobin, 4),
+            vec!["a:4444", "b:4444", "a:4444", "b:4444"]
+        );
+    }
+
+    #[test]
+    fn pick_follows_the_weights() {
+        let pool = pool(&["a:4444*3", "b:4444"]);
+
+        assert_eq!(
+            picks(&pool, Balancing::Weighted, 4),
+            vec!["a:4444", "a:4444", "a:4444", "b:4444"]
+        );
+    }
+
+    #[test]
+    fn pick_chooses_the_hub_with_the_least_active_sessions() {
+        let pool = pool(&["a:4444", "b:4444"]);
+        let mut active_sessions = HashMap::new();
+        active_sessions.insert("a:4444".to_string(), 2);
+        active_sessions.insert("b:4444".to_string(), 1);
+
+        assert_eq!(
+            pool.pick(Balancing::LeastSessions, &active_sessions),
+            Some("b:4444")
+        );
+    }
+
+    #[test]
+    fn pick_skips_the_unhealthy_hubs_unless_all_of_them_are() {
+        let pool = pool(&["a:4444", "b:4444"]);
+
+        pool.hubs()[0].set_healthy(false);
+        assert_eq!(
+            picks(&pool, Balancing::RoundRobin, 2),
+            vec!["b:4444", "b:4444"]
+        );
+
+        pool.hubs()[1].set_healthy(false);
+        assert_eq!(picks(&pool, Balancing::RoundRobin, 2).len(), 2);
+    }
+}
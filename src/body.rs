@@ -0,0 +1,93 @@
+use bytes::{Bytes, BytesMut};
+use futures::stream::{self, Stream, StreamExt};
+
+/// Default maximum size of a body read by the proxy to inspect it.
+pub const DEFAULT_INSPECTION_SIZE_CAP: usize = 1024 * 1024;
+
+/// The beginning of a body, read to inspect it, and the rest of the body
+/// which is still to be streamed (none when the body has been fully read).
+pub struct Prefix<S> {
+    pub bytes: Bytes,
+    pub rest: Option<S>,
+}
+
+impl<S, E> Prefix<S>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    /// Read the body until `cap` bytes, or until its end when it's smaller.
+    /// The prefix may exceed the cap by the size of the last chunk.
+    pub async fn read(mut stream: S, cap: usize) -> Result<Prefix<S>, E> {
+        let mut buffer = BytesMut::new();
+
+        while buffer.len() <= cap {
+            match stream.next().await {
+                Some(chunk) => buffer.extend_from_slice(&chunk?),
+                None => {
+                    return Ok(Prefix {
+                        bytes: buffer.freeze(),
+                        rest: None,
+                    })
+                }
+            }
+        }
+
+        Ok(Prefix {
+            bytes: buffer.freeze(),
+            rest: Some(stream),
+        })
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.rest.is_none()
+    }
+
+    /// The whole body again : the prefix followed by the rest of the stream.
+    pub fn into_stream(self) -> impl Stream<Item = Result<Bytes, E>> {
+        let prefix = Some(self.bytes).filter(|bytes| !bytes.is_empty());
+        stream::iter(prefix.map(Ok)).chain(stream::iter(self.rest).flatten())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunks(chunks: &[&'static str]) -> impl Stream<Item = Result<Bytes, ()>> + Unpin {
+        stream::iter(
+            chunks
+                .iter()
+                .map(|chunk| Ok(Bytes::from_static(chunk.as_bytes())))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    async fn collect<S: Stream<Item = Result<Bytes, ()>>>(stream: S) -> String {
+        let chunks: Vec<Result<Bytes, ()>> = stream.collect().await;
+        chunks
+            .into_iter()
+            .map(|chunk| String::from_utf8(chunk.unwrap().to_vec()).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn read_buffers_a_body_smaller_than_the_cap() {
+        let prefix = Prefix::read(chunks(&["{\"url\":", "\"https://duckduckgo.com\"}"]), 1024)
+            .await
+            .unwrap();
+
+        assert!(prefix.is_complete());
+        assert_eq!(prefix.bytes, "{\"url\":\"https://duckduckgo.com\"}");
+    }
+
+    #[tokio::test]
+    async fn read_stops_at_the_cap_and_keeps_the_rest_of_the_body() {
+        let prefix = Prefix::read(chunks(&["aaaa", "bbbb", "cccc", "dddd"]), 6)
+            .await
+            .unwrap();
+
+        assert!(!prefix.is_complete());
+        assert_eq!(prefix.bytes, "aaaabbbb");
+        assert_eq!(collect(prefix.into_stream()).await, "aaaabbbbccccdddd");
+    }
+}
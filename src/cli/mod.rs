@@ -1,5 +1,41 @@
+use crate::balancing::HubAddress;
+use crate::commands::CommandPattern;
+use crate::quotas::{Quota, Team};
+use crate::routing::Route;
+use crate::timeouts::TimeoutRule;
 use clap::{App, Arg, ArgMatches};
 
+fn validate_route(v: String) -> Result<(), String> {
+    v.parse::<Route>().map(|_| ())
+}
+
+fn validate_hub(v: String) -> Result<(), String> {
+    v.parse::<HubAddress>().map(|_| ())
+}
+
+fn validate_quota(v: String) -> Result<(), String> {
+    v.parse::<Quota>().map(|_| ())
+}
+
+fn validate_team(v: String) -> Result<(), String> {
+    v.parse::<Team>().map(|_| ())
+}
+
+fn validate_timeout_rule(v: String) -> Result<(), String> {
+    v.parse::<TimeoutRule>().map(|_| ())
+}
+
+fn validate_command(v: String) -> Result<(), String> {
+    v.parse::<CommandPattern>().map(|_| ())
+}
+
+fn validate_status(v: String) -> Result<(), String> {
+    match v.parse::<u16>() {
+        Ok(status) if (100..600).contains(&status) => Ok(()),
+        _ => Err(String::from("Format must be an HTTP status, e.g. 503")),
+    }
+}
+
 fn validate_format(v: String) -> Result<(), String> {
     if v.contains(':') {
         return Ok(());
@@ -8,38 +44,304 @@ fn validate_format(v: String) -> Result<(), String> {
 }
 
 pub fn init<'a>() -> ArgMatches<'a> {
+    app().get_matches()
+}
+
+/// The command line options. Every option but `--config`, `-v` and `-q` can
+/// also be set by the configuration file or an environment variable.
+pub fn app<'a, 'b>() -> App<'a, 'b> {
     App::new("HTTP Proxy")
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .help("Configuration file (.toml, .yaml or .yml) of the other options, reloaded on SIGHUP or when it changes")
+                .takes_value(true)
+                .required(false),
+        )
         .arg(
             Arg::with_name("listen")
                 .long("listen")
                 .help("format : IP:PORT")
                 .takes_value(true)
                 .validator(validate_format)
-                .required(true),
+                .required(false),
         )
         .arg(
             Arg::with_name("forward")
                 .long("forward")
-                .help("format : IP:PORT")
+                .help("Hub of the sessions, repeat it to share the sessions between several hubs, format : IP:PORT[*WEIGHT]")
                 .takes_value(true)
-                .validator(validate_format)
-                .required(true),
+                .multiple(true)
+                .number_of_values(1)
+                .validator(validate_hub)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("base-path")
+                .long("base-path")
+                .help("Prefix of the WebDriver endpoints of the hubs, also recognised besides /wd/hub and none, e.g. /selenium for /selenium/session or / for a Selenium 4 hub")
+                .takes_value(true)
+                .default_value("/wd/hub")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("balancing")
+                .long("balancing")
+                .help("How the new sessions are shared between the hubs of a pool")
+                .takes_value(true)
+                .possible_values(&["round-robin", "least-sessions", "weighted"])
+                .default_value("round-robin")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("health-check-interval")
+                .long("health-check-interval")
+                .help("Interval between two health checks of the hubs of a pool, format : DURATION_IN_SECS")
+                .takes_value(true)
+                .default_value("10")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("route")
+                .long("route")
+                .help("Forward the new sessions matching the capabilities to other hubs, format : CAPABILITY=VALUE[,CAPABILITY=VALUE...]@IP:PORT[*WEIGHT][,IP:PORT[*WEIGHT]...]")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(validate_route)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("quota")
+                .long("quota")
+                .help("Maximum concurrent sessions of a soda:user or of a team, GUEST for the sessions without soda:user, format : NAME=MAX_SESSIONS")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(validate_quota)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("team")
+                .long("team")
+                .help("soda:user values sharing the quota of a team, format : TEAM=USER[,USER...]")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(validate_team)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("grid-capacity")
+                .long("grid-capacity")
+                .help("Maximum sessions open on the grid, the next new sessions wait in a queue of the proxy, format : NUMBER_OF_SESSIONS")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("queue-timeout")
+                .long("queue-timeout")
+                .help("Maximum wait of a new session in the queue, format : DURATION_IN_SECS")
+                .takes_value(true)
+                .default_value("300")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("credentials")
+                .long("credentials")
+                .help("Authenticate the clients with HTTP Basic, file of USER:BCRYPT_HASH lines (htpasswd -B)")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("tokens")
+                .long("tokens")
+                .help("Authenticate the clients with bearer tokens, file of USER:SHA256_OF_THE_TOKEN lines")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("identity-mode")
+                .long("identity-mode")
+                .help("The authenticated user replaces soda:user (override), or a different soda:user is refused (validate)")
+                .takes_value(true)
+                .possible_values(&["override", "validate"])
+                .default_value("override")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("inspection-size-cap")
+                .long("inspection-size-cap")
+                .help("Maximum size of a body read to inspect it, the bigger bodies are streamed, format : SIZE_IN_BYTES")
+                .takes_value(true)
+                .default_value("1048576")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("timeline-size")
+                .long("timeline-size")
+                .help("Last commands kept in the timeline of each session, 0 to keep none, format : NUMBER_OF_COMMANDS")
+                .takes_value(true)
+                .default_value("200")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("screenshot-dir")
+                .long("screenshot-dir")
+                .help("Take a screenshot after the failed commands, saved as DIR/SESSION_ID/REQUEST_ID.png. The error is answered once the screenshot is taken, up to 5s later, at most one screenshot every 10s and 50 by session, format : DIR")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("screenshot-retention")
+                .long("screenshot-retention")
+                .help("Delete the screenshots older than this duration, format : DURATION_IN_SECS")
+                .takes_value(true)
+                .default_value("604800")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("pool-max-idle")
+                .long("pool-max-idle")
+                .help("Maximum idle connections kept alive to each hub, format : NUMBER_OF_CONNECTIONS")
+                .takes_value(true)
+                .default_value("32")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("pool-idle-timeout")
+                .long("pool-idle-timeout")
+                .help("Close the connections to the hubs idle for this duration, format : DURATION_IN_SECS")
+                .takes_value(true)
+                .default_value("90")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("retry-max")
+                .long("retry-max")
+                .help("Maximum retries of a command after the first try, format : NUMBER_OF_RETRIES")
+                .takes_value(true)
+                .default_value("3")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("retry-backoff")
+                .long("retry-backoff")
+                .help("Delay before the first retry, doubled for each next one and randomized, format : DURATION_IN_MILLIS")
+                .takes_value(true)
+                .default_value("100")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("retry-budget")
+                .long("retry-budget")
+                .help("A command isn't retried anymore after this duration since its first try, format : DURATION_IN_SECS")
+                .takes_value(true)
+                .default_value("10")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("retry-status")
+                .long("retry-status")
+                .help("Status of the hub retried like an error, replaces the default 502 and 503, format : STATUS")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(validate_status)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("retry-command")
+                .long("retry-command")
+                .help("Command safe to retry, replaces the default ones, format : METHOD /PATH_AFTER_THE_SESSION_ID (e.g. 'POST /element/*/click', '*' for a segment, '**' for the rest)")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(validate_command)
+                .required(false),
         )
         .arg(
             Arg::with_name("verbose")
                 .short("-v")
                 .long("verbose")
                 .multiple(true)
-                .help("Make the server more talkative")
+                .help("Make the server more talkative : -v logs every request, -vv the debug logs of the libraries too")
+                .takes_value(false)
+                .conflicts_with("quiet")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .short("-q")
+                .long("quiet")
+                .multiple(true)
+                .help("Make the server quieter : -q logs the warnings and the errors only, -qq the errors only")
                 .takes_value(false)
                 .required(false),
         )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .help("Level of the logs without -v nor -q, in the RUST_LOG syntax (e.g. debug or info,hyper=debug), RUST_LOG by default, otherwise info")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("log-format")
+                .long("log-format")
+                .help("Format of the logs : human readable text or one JSON object per line")
+                .takes_value(true)
+                .possible_values(&["text", "json"])
+                .default_value("text")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("idle-timeout")
+                .long("idle-timeout")
+                .help("Delete the sessions without any command for this duration, format : DURATION_IN_SECS")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("shutdown-timeout")
+                .long("shutdown-timeout")
+                .help("On SIGTERM or SIGINT, wait for the requests in flight during this duration at most, format : DURATION_IN_SECS")
+                .takes_value(true)
+                .default_value("30")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("shutdown-sessions")
+                .long("shutdown-sessions")
+                .help("What to do with the sessions still open on shutdown : keep them on the hubs, delete them, or hand them off to the next proxy through the hand-off file")
+                .takes_value(true)
+                .possible_values(&["keep", "delete", "handoff"])
+                .default_value("keep")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("handoff-file")
+                .long("handoff-file")
+                .help("File of the sessions handed off on shutdown, taken over by the proxy started next")
+                .takes_value(true)
+                .default_value("soda-sessions.json")
+                .required(false),
+        )
         .arg(
             Arg::with_name("timeout")
                 .long("timeout")
                 .help("format : DURATION_IN_SECS")
                 .takes_value(true)
-                .required(true),
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("timeout-rule")
+                .long("timeout-rule")
+                .help("Timeout of the commands matching a pattern instead of --timeout, the first matching rule is used, format : [USER@]METHOD /PATH_AFTER_THE_SESSION_ID=DURATION_IN_SECS (e.g. 'POST /execute/async=300')")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(validate_timeout_rule)
+                .required(false),
         )
-        .get_matches()
 }
@@ -0,0 +1,533 @@
+use crate::domain::CommandResponse;
+use crate::logging::{Event, Outcome};
+use crate::webdriver::{self, Route};
+use hyper::Method;
+use log::Level;
+use std::fmt;
+use std::str::FromStr;
+
+/// The WebDriver commands by method and path after the session id, named
+/// after the W3C specification. The JSONWP commands of the Selenium 3
+/// clients take the name of their W3C equivalent.
+const CATALOGUE: &[(&str, &str, &str)] = &[
+    ("GET", "/", "getSession"),
+    ("GET", "/timeouts", "getTimeouts"),
+    ("POST", "/timeouts", "setTimeouts"),
+    ("POST", "/url", "navigateTo"),
+    ("GET", "/url", "getCurrentUrl"),
+    ("POST", "/back", "back"),
+    ("POST", "/forward", "forward"),
+    ("POST", "/refresh", "refresh"),
+    ("GET", "/title", "getTitle"),
+    ("GET", "/window", "getWindowHandle"),
+    ("GET", "/window_handle", "getWindowHandle"),
+    ("DELETE", "/window", "closeWindow"),
+    ("POST", "/window", "switchToWindow"),
+    ("GET", "/window/handles", "getWindowHandles"),
+    ("GET", "/window_handles", "getWindowHandles"),
+    ("POST", "/window/new", "newWindow"),
+    ("GET", "/window/rect", "getWindowRect"),
+    ("POST", "/window/rect", "setWindowRect"),
+    ("POST", "/window/maximize", "maximizeWindow"),
+    ("POST", "/window/*/maximize", "maximizeWindow"),
+    ("POST", "/window/minimize", "minimizeWindow"),
+    ("POST", "/window/fullscreen", "fullscreenWindow"),
+    ("POST", "/frame", "switchToFrame"),
+    ("POST", "/frame/parent", "switchToParentFrame"),
+    ("POST", "/element", "findElement"),
+    ("POST", "/elements", "findElements"),
+    ("GET", "/element/active", "getActiveElement"),
+    ("POST", "/element/active", "getActiveElement"),
+    ("POST", "/element/*/element", "findElementFromElement"),
+    ("POST", "/element/*/elements", "findElementsFromElement"),
+    ("GET", "/element/*/selected", "isElementSelected"),
+    ("GET", "/element/*/attribute/*", "getElementAttribute"),
+    ("GET", "/element/*/property/*", "getElementProperty"),
+    ("GET", "/element/*/css/*", "getElementCssValue"),
+    ("GET", "/element/*/text", "getElementText"),
+    ("GET", "/element/*/name", "getElementTagName"),
+    ("GET", "/element/*/rect", "getElementRect"),
+    ("GET", "/element/*/enabled", "isElementEnabled"),
+    ("GET", "/element/*/displayed", "isElementDisplayed"),
+    ("POST", "/element/*/click", "elementClick"),
+    ("POST", "/element/*/clear", "elementClear"),
+    ("POST", "/element/*/value", "elementSendKeys"),
+    ("POST", "/element/*/submit", "elementSubmit"),
+    ("GET", "/element/*/screenshot", "takeElementScreenshot"),
+    ("GET", "/source", "getPageSource"),
+    ("POST", "/execute/sync", "executeScript"),
+    ("POST", "/execute", "executeScript"),
+    ("POST", "/execute/async", "executeAsyncScript"),
+    ("POST", "/execute_async", "executeAsyncScript"),
+    ("GET", "/cookie", "getAllCookies"),
+    ("GET", "/cookie/*", "getNamedCookie"),
+    ("POST", "/cookie", "addCookie"),
+    ("DELETE", "/cookie/*", "deleteCookie"),
+    ("DELETE", "/cookie", "deleteAllCookies"),
+    ("POST", "/actions", "performActions"),
+    ("DELETE", "/actions", "releaseActions"),
+    ("POST", "/keys", "sendKeysToActiveElement"),
+    ("POST", "/moveto", "moveTo"),
+    ("POST", "/alert/dismiss", "dismissAlert"),
+    ("POST", "/dismiss_alert", "dismissAlert"),
+    ("POST", "/alert/accept", "acceptAlert"),
+    ("POST", "/accept_alert", "acceptAlert"),
+    ("GET", "/alert/text", "getAlertText"),
+    ("GET", "/alert_text", "getAlertText"),
+    ("POST", "/alert/text", "sendAlertText"),
+    ("POST", "/alert_text", "sendAlertText"),
+    ("GET", "/screenshot", "takeScreenshot"),
+    ("POST", "/print", "printPage"),
+    ("POST", "/se/file", "uploadFile"),
+];
+
+/// WebDriver commands matched by their method and their path relative to the
+/// session, e.g. `POST /element/*/click`. `*` matches one segment of the path
+/// and a trailing `**` the rest of it, `*` as method matches any method.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CommandPattern {
+    method: Option<Method>,
+    segments: Vec<String>,
+}
+
+impl FromStr for CommandPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (method, path) = s
+            .trim()
+            .split_once(' ')
+            .ok_or_else(|| format!("{} must be METHOD /PATH", s))?;
+
+        let method = match method {
+            "*" => None,
+            method => Some(
+                Method::from_str(&method.to_uppercase())
+                    .map_err(|_| format!("Unknown method in {}", s))?,
+            ),
+        };
+
+        let path = path.trim();
+        if !path.starts_with('/') {
+            return Err(format!("The path of {} must start with /", s));
+        }
+
+        Ok(CommandPattern {
+            method,
+            segments: segments(path).map(String::from).collect(),
+        })
+    }
+}
+
+impl fmt::Display for CommandPattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.method {
+            Some(method) => write!(f, "{} /{}", method, self.segments.join("/")),
+            None => write!(f, "* /{}", self.segments.join("/")),
+        }
+    }
+}
+
+impl CommandPattern {
+    /// Whether the request is a command of a session matching the pattern.
+    pub fn matches(&self, method: &Method, path: &str, base_path: &str) -> bool {
+        if let Some(expected) = &self.method {
+            if expected != method {
+                return false;
+            }
+        }
+
+        match command_path(method, path, base_path) {
+            Some(command) => matches_segments(&self.segments, &command),
+            None => false,
+        }
+    }
+}
+
+/// A request classified by the command catalogue.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CommandEvent {
+    #[serde(skip)]
+    pub session_id: Option<String>,
+    /// Name of the command, e.g. `findElement`, `newSession` or `status`.
+    /// A command missing from the catalogue is named after its method and
+    /// its first segment, e.g. `POST moz`, and any other request `other`.
+    /// Only the logs and the timelines keep the name of such a command.
+    pub command: String,
+    /// The element of the command, e.g. the clicked one
+    pub element_id: Option<String>,
+    #[serde(skip)]
+    is_catalogued: bool,
+}
+
+/// The `command` label of the metrics for the commands missing from the
+/// catalogue, whose names come from the paths sent by the clients.
+pub const UNKNOWN_COMMAND: &str = "unknown";
+
+impl CommandEvent {
+    pub fn of(method: &Method, path: &str, base_path: &str) -> CommandEvent {
+        let route = Route::of(method, path, base_path);
+        let session_id = route.session_id().map(String::from);
+
+        let (command, element_id, is_catalogued) = match route {
+            Route::NewSession => ("newSession".to_string(), None, true),
+            Route::DeleteSession { .. } => ("deleteSession".to_string(), None, true),
+            Route::SessionCommand { command, .. } => {
+                let segments: Vec<&str> = segments(&command).collect();
+                let name = name_of(method, &segments);
+                let is_catalogued = name.is_some();
+                (
+                    name.unwrap_or_else(|| uncatalogued_name_of(method, &segments)),
+                    element_of(&segments),
+                    is_catalogued,
+                )
+            }
+            Route::Status => ("status".to_string(), None, true),
+            Route::Other => ("other".to_string(), None, true),
+        };
+
+        CommandEvent {
+            session_id,
+            command,
+            element_id,
+            is_catalogued,
+        }
+    }
+
+    /// The `command` label of the metrics : the name of the command, or
+    /// `unknown` when it's missing from the catalogue so that the clients
+    /// can't create new labels.
+    pub fn metric_label(&self) -> &str {
+        match self.is_catalogued {
+            true => &self.command,
+            false => UNKNOWN_COMMAND,
+        }
+    }
+}
+
+impl fmt::Display for CommandEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[COMMAND] [{}] {}",
+            self.session_id.as_deref().unwrap_or_default(),
+            self.command
+        )?;
+        if let Some(element_id) = &self.element_id {
+            write!(f, " (element: {})", element_id)?;
+        }
+        Ok(())
+    }
+}
+
+impl Event for CommandEvent {
+    fn name(&self) -> String {
+        "COMMAND".to_string()
+    }
+
+    fn session_id(&self) -> Option<&str> {
+        self.session_id.as_deref()
+    }
+}
+
+/// The response of the hub to a command, or the error of the proxy.
+/// The status and the duration are the ones of the outcome of the event.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CommandDoneEvent {
+    #[serde(skip)]
+    pub session_id: Option<String>,
+    pub command: String,
+    /// The WebDriver error code, e.g. `no such element` or `timeout`
+    pub error: Option<String>,
+    #[serde(skip)]
+    pub status: u16,
+    #[serde(skip)]
+    pub duration_ms: u64,
+    /// The screenshot of the session taken after the error, see `--screenshot-dir`
+    pub screenshot: Option<String>,
+}
+
+impl CommandDoneEvent {
+    /// The command is done, `body` is the beginning of the response of the
+    /// hub when it was read, the error is read from it.
+    pub fn new(command: &CommandEvent, outcome: &Outcome, body: &[u8]) -> CommandDoneEvent {
+        let error = match serde_json::from_slice::<CommandResponse>(body) {
+            Ok(response) => response.error(),
+            Err(_) if !outcome.status.is_success() => Some(webdriver::UNKNOWN_ERROR.to_string()),
+            Err(_) => None,
+        };
+
+        CommandDoneEvent::with_error(command, outcome, error)
+    }
+
+    pub fn with_error(
+        command: &CommandEvent,
+        outcome: &Outcome,
+        error: Option<String>,
+    ) -> CommandDoneEvent {
+        CommandDoneEvent {
+            session_id: command.session_id.to_owned(),
+            command: command.command.to_owned(),
+            error,
+            status: outcome.status.as_u16(),
+            duration_ms: outcome.duration.as_millis() as u64,
+            screenshot: None,
+        }
+    }
+
+    /// The commands in error are logged at info, the other ones at debug.
+    pub fn level(&self) -> Level {
+        match self.error {
+            Some(_) => Level::Info,
+            None => Level::Debug,
+        }
+    }
+}
+
+impl fmt::Display for CommandDoneEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[COMMAND_DONE] [{}] {} {}",
+            self.session_id.as_deref().unwrap_or_default(),
+            self.command,
+            self.status
+        )?;
+        if let Some(error) = &self.error {
+            write!(f, " {}", error)?;
+        }
+        write!(f, " in {}ms", self.duration_ms)?;
+        if let Some(screenshot) = &self.screenshot {
+            write!(f, ", screenshot {}", screenshot)?;
+        }
+        Ok(())
+    }
+}
+
+impl Event for CommandDoneEvent {
+    fn name(&self) -> String {
+        "COMMAND_DONE".to_string()
+    }
+
+    fn session_id(&self) -> Option<&str> {
+        self.session_id.as_deref()
+    }
+}
+
+/// Name of a command of a session, from the catalogue.
+fn name_of(method: &Method, command: &[&str]) -> Option<String> {
+    CATALOGUE
+        .iter()
+        .find(|(expected, template, _)| {
+            *expected == method.as_str()
+                && matches_segments(&segments(template).collect::<Vec<_>>(), command)
+        })
+        .map(|(_, _, name)| name.to_string())
+}
+
+/// Name of a command missing from the catalogue, after its method and the
+/// first segment of its path.
+fn uncatalogued_name_of(method: &Method, command: &[&str]) -> String {
+    match command.first() {
+        Some(segment) => format!("{} {}", method, segment),
+        None => format!("{} session", method),
+    }
+}
+
+/// The element following `/element/`, except the active one.
+fn element_of(command: &[&str]) -> Option<String> {
+    match command {
+        ["element", element_id, ..] if *element_id != "active" => Some(element_id.to_string()),
+        _ => None,
+    }
+}
+
+/// Segments of the path after the session id, none when the request isn't a
+/// command of a session (e.g. a new session or the status of the hub).
+fn command_path(method: &Method, path: &str, base_path: &str) -> Option<Vec<String>> {
+    match Route::of(method, path, base_path) {
+        Route::DeleteSession { .. } => Some(vec![]),
+        Route::SessionCommand { command, .. } => {
+            Some(segments(&command).map(String::from).collect())
+        }
+        _ => None,
+    }
+}
+
+fn segments(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|segment| !segment.is_empty())
+}
+
+fn matches_segments<P: AsRef<str>, S: AsRef<str>>(pattern: &[P], segments: &[S]) -> bool {
+    match (pattern.split_first(), segments.split_first()) {
+        (Some((wildcard, _)), _) if wildcard.as_ref() == "**" => true,
+        (Some((expected, pattern)), Some((segment, segments)))
+            if expected.as_ref() == "*" || expected.as_ref() == segment.as_ref() =>
+        {
+            matches_segments(pattern, segments)
+        }
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webdriver::DEFAULT_BASE_PATH;
+    use hyper::StatusCode;
+    use std::time::Duration;
+
+    #[test]
+    fn command_pattern_is_parsed_from_the_method_and_the_path() {
+        assert_eq!(
+            "POST /element/*/click".parse::<CommandPattern>(),
+            Ok(CommandPattern {
+                method: Some(Method::POST),
+                segments: vec!["element".to_string(), "*".to_string(), "click".to_string()],
+            })
+        );
+        assert!("* /**".parse::<CommandPattern>().is_ok());
+        assert!("POST".parse::<CommandPattern>().is_err());
+        assert!("POST element".parse::<CommandPattern>().is_err());
+    }
+
+    #[test]
+    fn command_pattern_matches_the_path_after_the_session_id() {
+        let click: CommandPattern = "POST /element/*/click".parse().unwrap();
+        let any_get: CommandPattern = "GET /**".parse().unwrap();
+        let matches = |pattern: &CommandPattern, method: Method, path: &str| {
+            pattern.matches(&method, path, DEFAULT_BASE_PATH)
+        };
+
+        assert!(matches(
+            &click,
+            Method::POST,
+            "/wd/hub/session/123/element/456/click"
+        ));
+        assert!(!matches(
+            &click,
+            Method::POST,
+            "/wd/hub/session/123/element"
+        ));
+        assert!(!matches(
+            &click,
+            Method::GET,
+            "/wd/hub/session/123/element/456/click"
+        ));
+        assert!(matches(&any_get, Method::GET, "/wd/hub/session/123"));
+        assert!(matches(
+            &any_get,
+            Method::GET,
+            "/wd/hub/session/123/element/456/text?x=1"
+        ));
+        assert!(!matches(&any_get, Method::GET, "/wd/hub/status"));
+        assert!(matches(
+            &click,
+            Method::POST,
+            "/session/123/element/456/click"
+        ));
+    }
+
+    #[test]
+    fn command_event_names_the_w3c_and_jsonwp_commands() {
+        let command =
+            |method: Method, path: &str| CommandEvent::of(&method, path, DEFAULT_BASE_PATH).command;
+
+        assert_eq!(command(Method::POST, "/session"), "newSession");
+        assert_eq!(
+            command(Method::POST, "/wd/hub/session/123/url"),
+            "navigateTo"
+        );
+        assert_eq!(command(Method::GET, "/session/123/url"), "getCurrentUrl");
+        assert_eq!(command(Method::POST, "/session/123/element"), "findElement");
+        assert_eq!(
+            command(Method::GET, "/session/123/screenshot"),
+            "takeScreenshot"
+        );
+        assert_eq!(
+            command(Method::POST, "/session/123/execute/sync"),
+            "executeScript"
+        );
+        assert_eq!(
+            command(Method::POST, "/wd/hub/session/123/execute"),
+            "executeScript"
+        );
+        assert_eq!(
+            command(Method::POST, "/wd/hub/session/123/accept_alert"),
+            "acceptAlert"
+        );
+        assert_eq!(
+            command(Method::GET, "/session/123/element/456/attribute/url"),
+            "getElementAttribute"
+        );
+        assert_eq!(command(Method::GET, "/session/123"), "getSession");
+        assert_eq!(command(Method::DELETE, "/session/123"), "deleteSession");
+        assert_eq!(
+            command(Method::POST, "/session/123/moz/context"),
+            "POST moz"
+        );
+        assert_eq!(command(Method::GET, "/wd/hub/status"), "status");
+        assert_eq!(
+            CommandEvent::of(&Method::POST, "/session/123/moz/context", DEFAULT_BASE_PATH)
+                .metric_label(),
+            UNKNOWN_COMMAND
+        );
+        assert_eq!(
+            CommandEvent::of(&Method::POST, "/session/123/url", DEFAULT_BASE_PATH).metric_label(),
+            "navigateTo"
+        );
+        assert_eq!(command(Method::GET, "/favicon.ico"), "other");
+
+        let click = CommandEvent::of(
+            &Method::POST,
+            "/session/123/element/456/click",
+            DEFAULT_BASE_PATH,
+        );
+        assert_eq!(click.command, "elementClick");
+        assert_eq!(click.session_id, Some("123".to_string()));
+        assert_eq!(click.element_id, Some("456".to_string()));
+        assert_eq!(
+            CommandEvent::of(
+                &Method::GET,
+                "/session/123/element/active",
+                DEFAULT_BASE_PATH
+            )
+            .element_id,
+            None
+        );
+    }
+
+    #[test]
+    fn command_done_event_reads_the_webdriver_error_of_the_response() {
+        let click = CommandEvent::of(
+            &Method::POST,
+            "/session/123/element/456/click",
+            DEFAULT_BASE_PATH,
+        );
+        let outcome = |status: StatusCode| Outcome {
+            status,
+            duration: Duration::from_millis(12),
+        };
+
+        let done = CommandDoneEvent::new(
+            &click,
+            &outcome(StatusCode::NOT_FOUND),
+            br#"{"value":{"error":"no such element","message":"","stacktrace":""}}"#,
+        );
+        assert_eq!(done.error, Some("no such element".to_string()));
+        assert_eq!(done.level(), Level::Info);
+        assert_eq!(
+            done.to_string(),
+            "[COMMAND_DONE] [123] elementClick 404 no such element in 12ms"
+        );
+
+        let streamed = CommandDoneEvent::new(&click, &outcome(StatusCode::OK), b"");
+        assert_eq!(streamed.error, None);
+        assert_eq!(streamed.level(), Level::Debug);
+
+        let bad_gateway =
+            CommandDoneEvent::new(&click, &outcome(StatusCode::BAD_GATEWAY), b"Bad Gateway");
+        assert_eq!(bad_gateway.error, Some("unknown error".to_string()));
+    }
+}
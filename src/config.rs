@@ -0,0 +1,744 @@
+use crate::auth::{Authenticator, IdentityMode};
+use crate::balancing::{Balancing, HubAddress};
+use crate::body;
+use crate::commands::CommandPattern;
+use crate::quotas::{Quota, Quotas, Team};
+use crate::retries::RetryPolicy;
+use crate::routing::{Route, Router};
+use crate::screenshots;
+use crate::timeline;
+use crate::timeouts::{TimeoutRule, Timeouts};
+use crate::webdriver::{self, BasePath};
+use crate::AppState;
+use clap::ArgMatches;
+use hyper::StatusCode;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task;
+use tokio::time;
+
+/// The settings of the configuration file and of the environment variables,
+/// named after the command line options.
+const KEYS: &[&str] = &[
+    "listen",
+    "forward",
+    "base-path",
+    "balancing",
+    "health-check-interval",
+    "route",
+    "quota",
+    "team",
+    "grid-capacity",
+    "queue-timeout",
+    "credentials",
+    "tokens",
+    "identity-mode",
+    "inspection-size-cap",
+    "timeline-size",
+    "screenshot-dir",
+    "screenshot-retention",
+    "pool-max-idle",
+    "pool-idle-timeout",
+    "retry-max",
+    "retry-backoff",
+    "retry-budget",
+    "retry-status",
+    "retry-command",
+    "log-format",
+    "log-level",
+    "idle-timeout",
+    "shutdown-timeout",
+    "shutdown-sessions",
+    "handoff-file",
+    "timeout",
+    "timeout-rule",
+];
+
+const REQUIRED_KEYS: &[&str] = &["listen", "forward", "timeout"];
+
+/// The settings read at startup only, a reload doesn't change them.
+const RESTART_KEYS: &[&str] = &[
+    "listen",
+    "health-check-interval",
+    "pool-max-idle",
+    "pool-idle-timeout",
+    "log-format",
+    "log-level",
+    "idle-timeout",
+    "shutdown-timeout",
+    "shutdown-sessions",
+    "handoff-file",
+];
+
+/// Separator of the values of a list in an environment variable,
+/// e.g. `SODA_QUOTA="team-a=10;team-b=5"`
+const ENV_SEPARATOR: char = ';';
+
+/// Interval between two checks of the modification time of the configuration file.
+const FILE_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Debug, PartialEq)]
+enum Source {
+    CommandLine,
+    Environment(String),
+    File(String),
+    Default,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::CommandLine => write!(f, "the command line"),
+            Source::Environment(name) => write!(f, "${}", name),
+            Source::File(path) => write!(f, "{}", path),
+            Source::Default => write!(f, "the default value"),
+        }
+    }
+}
+
+/// The values of every setting, and where they come from.
+pub struct Config {
+    file: Option<String>,
+    values: BTreeMap<&'static str, (Vec<String>, Source)>,
+}
+
+impl Config {
+    /// Read the settings, by order of precedence : the command line, the
+    /// `SODA_*` environment variables, the configuration file, then the
+    /// defaults of the command line.
+    pub fn load(matches: &ArgMatches) -> Result<Config, String> {
+        let file = matches.value_of("config").map(String::from);
+        let from_file = match &file {
+            Some(path) => read_file(path)?,
+            None => BTreeMap::new(),
+        };
+
+        Config::resolve(matches, file, from_file, |name| env::var(name).ok())
+    }
+
+    fn resolve<F: Fn(&str) -> Option<String>>(
+        matches: &ArgMatches,
+        file: Option<String>,
+        from_file: BTreeMap<String, Vec<String>>,
+        env: F,
+    ) -> Result<Config, String> {
+        if let Some(key) = from_file.keys().find(|key| !KEYS.contains(&key.as_str())) {
+            return Err(format!(
+                "{} : unknown setting {}",
+                file.unwrap_or_default(),
+                key
+            ));
+        }
+
+        let mut values = BTreeMap::new();
+        for key in KEYS {
+            let of_matches = || {
+                matches
+                    .values_of(key)
+                    .map(|values| values.map(String::from).collect::<Vec<_>>())
+            };
+
+            let value = match (matches.occurrences_of(key), env(&env_name(key))) {
+                (0, Some(value)) => Some((
+                    value
+                        .split(ENV_SEPARATOR)
+                        .map(str::trim)
+                        .filter(|value| !value.is_empty())
+                        .map(String::from)
+                        .collect(),
+                    Source::Environment(env_name(key)),
+                )),
+                (0, None) => match from_file.get(*key) {
+                    Some(value) => Some((
+                        value.to_owned(),
+                        Source::File(file.to_owned().unwrap_or_default()),
+                    )),
+                    None => of_matches().map(|value| (value, Source::Default)),
+                },
+                _ => of_matches().map(|value| (value, Source::CommandLine)),
+            };
+
+            if let Some(value) = value {
+                values.insert(*key, value);
+            }
+        }
+
+        let config = Config { file, values };
+        for key in REQUIRED_KEYS {
+            if config.value(key).is_none() {
+                return Err(format!(
+                    "{} is required : --{}, ${} or {} in the configuration file",
+                    key,
+                    key,
+                    env_name(key),
+                    key
+                ));
+            }
+        }
+
+        Ok(config)
+    }
+
+    pub fn file(&self) -> Option<&str> {
+        self.file.as_deref()
+    }
+
+    pub fn value(&self, key: &str) -> Option<&str> {
+        self.values
+            .get(key)
+            .and_then(|(values, _)| values.last())
+            .map(String::as_str)
+    }
+
+    /// The value of a setting with a single value.
+    pub fn parse<T>(&self, key: &str) -> Result<Option<T>, String>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        match self.values.get(key) {
+            Some((values, source)) if values.len() > 1 => Err(format!(
+                "{} (from {}) : a single value is expected, not {:?}",
+                key, source, values
+            )),
+            Some((values, source)) => match values.first() {
+                Some(value) => value
+                    .parse()
+                    .map(Some)
+                    .map_err(|err| invalid(key, value, source, err)),
+                None => Ok(None),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// The values of a setting with a list of values.
+    pub fn parse_all<T>(&self, key: &str) -> Result<Vec<T>, String>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        match self.values.get(key) {
+            Some((values, source)) => values
+                .iter()
+                .map(|value| {
+                    value
+                        .parse()
+                        .map_err(|err| invalid(key, value, source, err))
+                })
+                .collect(),
+            None => Ok(vec![]),
+        }
+    }
+
+    fn differs(&self, other: &Config, key: &str) -> bool {
+        self.values.get(key).map(|(values, _)| values)
+            != other.values.get(key).map(|(values, _)| values)
+    }
+}
+
+fn invalid<E: fmt::Display>(key: &str, value: &str, source: &Source, err: E) -> String {
+    format!("Invalid {} {} (from {}) : {}", key, value, source, err)
+}
+
+/// `grid-capacity` is set by `SODA_GRID_CAPACITY`
+fn env_name(key: &str) -> String {
+    format!("SODA_{}", key.to_uppercase().replace('-', "_"))
+}
+
+/// The settings of a TOML file, or of a YAML file (`.yaml` or `.yml`).
+/// A key may be written `grid-capacity` or `grid_capacity`.
+fn read_file(path: &str) -> Result<BTreeMap<String, Vec<String>>, String> {
+    let content = fs::read_to_string(path).map_err(|err| format!("{} : {}", path, err))?;
+    let document: Value = match path.ends_with(".yaml") || path.ends_with(".yml") {
+        true => serde_yaml::from_str(&content).map_err(|err| format!("{} : {}", path, err))?,
+        false => toml::from_str(&content).map_err(|err| format!("{} : {}", path, err))?,
+    };
+
+    let settings = match document {
+        Value::Object(settings) => settings,
+        Value::Null => Map::new(),
+        _ => return Err(format!("{} : the settings must be KEY = VALUE pairs", path)),
+    };
+
+    let mut values = BTreeMap::new();
+    for (key, value) in settings {
+        let key = key.replace('_', "-");
+        let value = match value {
+            Value::Array(value) => value
+                .into_iter()
+                .map(|value| scalar(path, &key, value))
+                .collect::<Result<Vec<_>, _>>()?,
+            value => vec![scalar(path, &key, value)?],
+        };
+        values.insert(key, value);
+    }
+
+    Ok(values)
+}
+
+fn scalar(path: &str, key: &str, value: Value) -> Result<String, String> {
+    match value {
+        Value::String(value) => Ok(value),
+        Value::Number(value) => Ok(value.to_string()),
+        Value::Bool(value) => Ok(value.to_string()),
+        _ => Err(format!(
+            "{} : {} must be a string, a number or a list of them",
+            path, key
+        )),
+    }
+}
+
+/// The settings which can change while the proxy is running. A request keeps
+/// the settings it started with until its end.
+pub struct Settings {
+    /// First hub of `--forward`, for the requests outside of a session
+    pub forward_uri: String,
+    /// Prefix of the WebDriver endpoints of the hubs, recognised besides
+    /// `/wd/hub` and none, e.g. `/selenium`
+    pub base_path: String,
+    pub router: Router,
+    pub quotas: Quotas,
+    pub auth: Authenticator,
+    pub timeouts: Timeouts,
+    pub retry: RetryPolicy,
+    pub inspection_size_cap: usize,
+    pub grid_capacity: Option<usize>,
+    pub queue_timeout: Duration,
+    /// Commands kept in the timeline of each session
+    pub timeline_size: usize,
+    /// Directory of the screenshots taken after the failed commands, none
+    /// are taken without it
+    pub screenshot_dir: Option<PathBuf>,
+    /// How long the screenshots are kept
+    pub screenshot_retention: Duration,
+}
+
+impl Settings {
+    /// A single hub, without any route, quota, queue or authentication.
+    pub fn new(forward_uri: String, timeout: u32) -> Settings {
+        Settings {
+            forward_uri: forward_uri.to_owned(),
+            base_path: webdriver::DEFAULT_BASE_PATH.to_string(),
+            router: Router::new(
+                vec![HubAddress {
+                    address: forward_uri,
+                    weight: 1,
+                }],
+                vec![],
+                Balancing::RoundRobin,
+            ),
+            quotas: Quotas::default(),
+            auth: Authenticator::default(),
+            timeouts: Timeouts::new(Duration::from_secs(timeout.into()), vec![]),
+            retry: RetryPolicy::default(),
+            inspection_size_cap: body::DEFAULT_INSPECTION_SIZE_CAP,
+            grid_capacity: None,
+            queue_timeout: Duration::from_secs(0),
+            timeline_size: timeline::DEFAULT_TIMELINE_SIZE,
+            screenshot_dir: None,
+            screenshot_retention: Duration::from_secs(screenshots::DEFAULT_RETENTION),
+        }
+    }
+
+    /// Validate the configuration, every invalid value is an error.
+    pub fn from_config(config: &Config) -> Result<Settings, String> {
+        let forwarded = config
+            .parse_all::<HubAddress>("forward")?
+            .iter()
+            .map(HubAddress::resolve)
+            .collect::<Result<Vec<_>, String>>()?;
+        let timeout = config.parse::<u32>("timeout")?.unwrap_or(60);
+        let base_path = config
+            .parse::<BasePath>("base-path")?
+            .map(|base_path| base_path.0)
+            .unwrap_or_else(|| webdriver::DEFAULT_BASE_PATH.to_string());
+
+        // Give more time to the slow commands, e.g. the async scripts
+        let timeout_rules = config.parse_all::<TimeoutRule>("timeout-rule")?;
+        for rule in &timeout_rules {
+            info!("Timeout rule : {}", rule);
+        }
+
+        // Route the new sessions to other hubs than the default one, resolved
+        // like the default ones
+        let routes = config
+            .parse_all::<Route>("route")?
+            .into_iter()
+            .map(|route| {
+                let hubs = route
+                    .hubs
+                    .iter()
+                    .map(HubAddress::resolve)
+                    .collect::<Result<Vec<_>, String>>()?;
+                Ok(Route { hubs, ..route })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        for route in &routes {
+            info!(
+                "Sessions matching {:?} will be forwarded to {:?}",
+                route.matchers, route.hubs
+            );
+        }
+
+        // Share the new sessions between the hubs of a pool
+        let balancing = config
+            .parse::<Balancing>("balancing")?
+            .unwrap_or(Balancing::RoundRobin);
+
+        // Limit the concurrent sessions of the users and of the teams
+        let quotas = config.parse_all::<Quota>("quota")?;
+        let teams = config.parse_all::<Team>("team")?;
+        for quota in &quotas {
+            info!(
+                "{} is limited to {} concurrent sessions",
+                quota.name, quota.max_sessions
+            );
+        }
+
+        // Hold the new sessions in a fair queue when the grid is full
+        let grid_capacity = config.parse::<usize>("grid-capacity")?;
+        let queue_timeout = config.parse::<u64>("queue-timeout")?.unwrap_or(300);
+        if let Some(grid_capacity) = grid_capacity {
+            info!(
+                "New sessions will wait up to {}s in the queue when {} sessions are open",
+                queue_timeout, grid_capacity
+            );
+        }
+
+        // Authenticate the clients, their identity becomes the soda:user
+        let identity_mode = config
+            .parse::<IdentityMode>("identity-mode")?
+            .unwrap_or(IdentityMode::Override);
+        let auth = Authenticator::from_files(
+            config.value("credentials"),
+            config.value("tokens"),
+            identity_mode,
+        )
+        .map_err(|err| format!("Fail to read the credentials : {}", err))?;
+        if auth.is_enabled() {
+            info!(
+                "The clients must be authenticated, soda:user mode : {:?}",
+                identity_mode
+            );
+        }
+
+        // Retry the commands without side effects when the hub fails
+        let mut retry = RetryPolicy::default();
+        retry.max_retries = config
+            .parse::<usize>("retry-max")?
+            .unwrap_or(retry.max_retries);
+        if let Some(backoff) = config.parse::<u64>("retry-backoff")? {
+            retry.backoff = Duration::from_millis(backoff);
+        }
+        if let Some(budget) = config.parse::<u64>("retry-budget")? {
+            retry.budget = Duration::from_secs(budget);
+        }
+        let statuses = config.parse_all::<StatusCode>("retry-status")?;
+        if !statuses.is_empty() {
+            retry.statuses = statuses;
+        }
+        let commands = config.parse_all::<CommandPattern>("retry-command")?;
+        if !commands.is_empty() {
+            retry.commands = commands;
+        }
+
+        // Keep the evidence of the failed commands
+        let screenshot_dir = config.value("screenshot-dir").map(PathBuf::from);
+        let screenshot_retention = config
+            .parse::<u64>("screenshot-retention")?
+            .unwrap_or(screenshots::DEFAULT_RETENTION);
+        if let Some(dir) = &screenshot_dir {
+            info!(
+                "A screenshot will be taken after the failed commands, in {}, kept {}s",
+                dir.display(),
+                screenshot_retention
+            );
+        }
+
+        Ok(Settings {
+            forward_uri: forwarded[0].address.to_owned(),
+            base_path,
+            router: Router::new(forwarded, routes, balancing),
+            quotas: Quotas::new(quotas, teams),
+            auth,
+            timeouts: Timeouts::new(Duration::from_secs(timeout.into()), timeout_rules),
+            retry,
+            // Only the beginning of the bodies is read to inspect them
+            inspection_size_cap: config
+                .parse::<usize>("inspection-size-cap")?
+                .unwrap_or(body::DEFAULT_INSPECTION_SIZE_CAP),
+            grid_capacity,
+            queue_timeout: Duration::from_secs(queue_timeout),
+            timeline_size: config
+                .parse::<usize>("timeline-size")?
+                .unwrap_or(timeline::DEFAULT_TIMELINE_SIZE),
+            screenshot_dir,
+            screenshot_retention: Duration::from_secs(screenshot_retention),
+        })
+    }
+}
+
+/// Reload the configuration on SIGHUP, or when the configuration file, the
+/// credentials file or the tokens file changes. The open sessions, the queue
+/// and the requests in progress are kept, an invalid configuration is ignored.
+pub async fn run_reloads(state: Arc<AppState>, matches: ArgMatches<'static>, mut config: Config) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => {
+            error!("The configuration can't be reloaded on SIGHUP : {}", err);
+            return;
+        }
+    };
+    let mut files = watched_files(&config);
+
+    loop {
+        let reload = tokio::select! {
+            _ = hangup.recv() => {
+                info!("SIGHUP received, reloading the configuration");
+                true
+            }
+            _ = time::delay_for(FILE_CHECK_INTERVAL) => {
+                let changed = files
+                    .iter()
+                    .find(|(file, modified)| modified_time(file) != *modified);
+                if let Some((file, _)) = changed {
+                    info!("{} changed, reloading the configuration", file);
+                }
+                changed.is_some()
+            }
+        };
+        if !reload {
+            continue;
+        }
+        files = watched_files(&config);
+
+        // The files are read and the hubs resolved on the blocking threads
+        let matches = matches.clone();
+        let reloaded = task::spawn_blocking(move || {
+            Config::load(&matches).and_then(|reloaded| {
+                Settings::from_config(&reloaded).map(|settings| (reloaded, settings))
+            })
+        })
+        .await
+        .unwrap_or_else(|err| Err(format!("The reload failed : {}", err)));
+        match reloaded {
+            Ok((reloaded, settings)) => {
+                for key in RESTART_KEYS {
+                    if reloaded.differs(&config, key) {
+                        warn!(
+                            "{} is only read at startup, restart the proxy to change it",
+                            key
+                        );
+                    }
+                }
+                state.set_settings(settings);
+                config = reloaded;
+                files = watched_files(&config);
+                info!("Configuration reloaded");
+            }
+            Err(err) => error!(
+                "The configuration is not reloaded, the previous one is kept : {}",
+                err
+            ),
+        }
+    }
+}
+
+/// The files read by the configuration, with their modification time.
+fn watched_files(config: &Config) -> Vec<(String, Option<SystemTime>)> {
+    config
+        .file()
+        .into_iter()
+        .chain(config.value("credentials"))
+        .chain(config.value("tokens"))
+        .map(|file| (file.to_string(), modified_time(file)))
+        .collect()
+}
+
+fn modified_time(path: &str) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli;
+    use std::collections::HashMap;
+
+    fn resolve(
+        args: &[&str],
+        file: &[(&str, &[&str])],
+        env: &[(&str, &str)],
+    ) -> Result<Config, String> {
+        let matches = cli::app()
+            .get_matches_from(std::iter::once("soda-test-service").chain(args.iter().copied()));
+        let from_file = file
+            .iter()
+            .map(|(key, values)| {
+                (
+                    key.to_string(),
+                    values.iter().map(|value| value.to_string()).collect(),
+                )
+            })
+            .collect();
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+
+        Config::resolve(&matches, Some("soda.toml".to_string()), from_file, |name| {
+            env.get(name).cloned()
+        })
+    }
+
+    #[test]
+    fn resolve_prefers_the_command_line_then_the_environment_then_the_file() {
+        let config = resolve(
+            &["--timeout=30"],
+            &[
+                ("listen", &["0.0.0.0:8080"]),
+                ("forward", &["127.0.0.1:4444"]),
+                ("timeout", &["60"]),
+                ("quota", &["team-a=10"]),
+            ],
+            &[("SODA_TIMEOUT", "45"), ("SODA_QUOTA", "team-b=5;GUEST=1")],
+        )
+        .unwrap();
+
+        assert_eq!(config.value("listen"), Some("0.0.0.0:8080"));
+        assert_eq!(config.parse::<u32>("timeout"), Ok(Some(30)));
+        assert_eq!(
+            config.parse_all::<Quota>("quota").unwrap(),
+            vec!["team-b=5".parse().unwrap(), "GUEST=1".parse().unwrap()]
+        );
+        // The defaults of the command line
+        assert_eq!(config.parse::<usize>("retry-max"), Ok(Some(3)));
+    }
+
+    #[test]
+    fn resolve_rejects_an_unknown_or_missing_setting() {
+        let unknown = resolve(
+            &[
+                "--listen=0.0.0.0:8080",
+                "--forward=127.0.0.1:4444",
+                "--timeout=60",
+            ],
+            &[("quotas", &["team-a=10"])],
+            &[],
+        );
+        assert_eq!(
+            unknown.err(),
+            Some("soda.toml : unknown setting quotas".to_string())
+        );
+
+        let missing = resolve(&["--listen=0.0.0.0:8080", "--timeout=60"], &[], &[]);
+        assert!(missing.err().unwrap().starts_with("forward is required"));
+    }
+
+    #[test]
+    fn read_file_accepts_toml_and_yaml() {
+        let toml_path = env::temp_dir().join(format!("soda-{}.toml", std::process::id()));
+        let yaml_path = env::temp_dir().join(format!("soda-{}.yaml", std::process::id()));
+        fs::write(
+            &toml_path,
+            "timeout = 60\nforward = [\"127.0.0.1:4444\", \"127.0.0.1:5555*2\"]\n",
+        )
+        .unwrap();
+        fs::write(
+            &yaml_path,
+            "timeout: 60\ngrid_capacity: 50\nforward:\n  - 127.0.0.1:4444\n",
+        )
+        .unwrap();
+
+        let toml = read_file(toml_path.to_str().unwrap()).unwrap();
+        let yaml = read_file(yaml_path.to_str().unwrap()).unwrap();
+        fs::remove_file(toml_path).unwrap();
+        fs::remove_file(yaml_path).unwrap();
+
+        assert_eq!(toml["timeout"], vec!["60"]);
+        assert_eq!(toml["forward"], vec!["127.0.0.1:4444", "127.0.0.1:5555*2"]);
+        assert_eq!(yaml["grid-capacity"], vec!["50"]);
+        assert_eq!(yaml["forward"], vec!["127.0.0.1:4444"]);
+    }
+
+    #[test]
+    fn settings_report_the_invalid_value_and_its_source() {
+        let config = resolve(
+            &[
+                "--listen=0.0.0.0:8080",
+                "--forward=127.0.0.1:4444",
+                "--timeout=60",
+            ],
+            &[("timeout-rule", &["POST /execute/async=forever"])],
+            &[],
+        )
+        .unwrap();
+
+        let err = Settings::from_config(&config).err().unwrap();
+
+        assert!(
+            err.starts_with("Invalid timeout-rule POST /execute/async=forever (from soda.toml)")
+        );
+    }
+
+    #[test]
+    fn settings_resolve_the_hubs_of_the_routes_like_the_default_ones() {
+        let config = resolve(
+            &[
+                "--listen=0.0.0.0:8080",
+                "--forward=localhost:4444",
+                "--timeout=60",
+                "--route=browserName=firefox@localhost:4444*2",
+            ],
+            &[],
+            &[],
+        )
+        .unwrap();
+
+        let settings = Settings::from_config(&config).unwrap();
+
+        let addresses: Vec<&str> = settings
+            .router
+            .hubs()
+            .iter()
+            .map(|hub| hub.address.as_str())
+            .collect();
+        assert_eq!(addresses.len(), 2);
+        assert_eq!(addresses[0], settings.forward_uri);
+        assert_eq!(addresses[1], settings.forward_uri);
+        assert!(!settings.forward_uri.starts_with("localhost"));
+    }
+
+    #[test]
+    fn settings_read_the_base_path_of_the_hubs() {
+        let args = &[
+            "--listen=0.0.0.0:8080",
+            "--forward=127.0.0.1:4444",
+            "--timeout=60",
+        ];
+        let settings = |file: &[(&str, &[&str])]| {
+            Settings::from_config(&resolve(args, file, &[]).unwrap()).map(|s| s.base_path)
+        };
+
+        assert_eq!(settings(&[]).unwrap(), "/wd/hub");
+        assert_eq!(
+            settings(&[("base-path", &["/selenium/"])]).unwrap(),
+            "/selenium"
+        );
+        assert_eq!(settings(&[("base-path", &["/"])]).unwrap(), "");
+        assert!(settings(&[("base-path", &["selenium"])])
+            .unwrap_err()
+            .starts_with("Invalid base-path selenium (from soda.toml)"));
+    }
+}
@@ -1,25 +1,78 @@
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
 use std::fmt;
-#[derive(Deserialize)]
+
+/// Prefix of the vendor capabilities owned by SODA (e.g. `soda:user`).
+const SODA_PREFIX: &str = "soda:";
+
+/// Body of a new session request.
+/// A client can send the W3C `capabilities` envelope, the legacy JSONWP
+/// `desiredCapabilities` object, or both (Selenium 3 clients do).
+#[derive(Default, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Capabilities {
-    pub desired_capabilities: DesiredCapabilities,
+    pub capabilities: Option<W3cCapabilities>,
+    pub desired_capabilities: Option<Map<String, Value>>,
+}
+
+/// The W3C `capabilities` envelope.
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct W3cCapabilities {
+    pub always_match: Option<Map<String, Value>>,
+    pub first_match: Option<Vec<Map<String, Value>>>,
 }
 
-#[derive(Deserialize, Clone, PartialEq)]
+#[derive(Default, Clone, PartialEq, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct DesiredCapabilities {
     pub browser_name: Option<String>,
+    pub browser_version: Option<String>,
     pub platform: Option<String>,
-    #[serde(rename(deserialize = "soda:user"))]
     pub soda_user: Option<String>,
+    /// Every `soda:*` vendor capability, keyed by its full name.
+    pub soda: BTreeMap<String, String>,
 }
 
 impl DesiredCapabilities {
-    fn new() -> DesiredCapabilities {
+    /// Read the capabilities we track from a JSON object.
+    /// Both the W3C names (`platformName`, `browserVersion`) and the
+    /// JSONWP ones (`platform`, `version`) are supported, W3C first.
+    pub fn from_map(map: &Map<String, Value>) -> DesiredCapabilities {
+        let string_of = |key: &str| map.get(key).and_then(Value::as_str).map(String::from);
+
+        let soda: BTreeMap<String, String> = map
+            .iter()
+            .filter(|(key, _)| key.starts_with(SODA_PREFIX))
+            .map(|(key, value)| {
+                let value = match value {
+                    Value::String(s) => s.to_owned(),
+                    other => other.to_string(),
+                };
+                (key.to_owned(), value)
+            })
+            .collect();
+
+        DesiredCapabilities {
+            browser_name: string_of("browserName"),
+            browser_version: string_of("browserVersion").or_else(|| string_of("version")),
+            platform: string_of("platformName").or_else(|| string_of("platform")),
+            soda_user: string_of("soda:user"),
+            soda,
+        }
+    }
+
+    /// Fill the missing capabilities with the ones of `other`.
+    pub fn or(self, other: DesiredCapabilities) -> DesiredCapabilities {
+        let mut soda = other.soda;
+        soda.extend(self.soda);
+
         DesiredCapabilities {
-            browser_name: None,
-            platform: None,
-            soda_user: None,
+            browser_name: self.browser_name.or(other.browser_name),
+            browser_version: self.browser_version.or(other.browser_version),
+            platform: self.platform.or(other.platform),
+            soda_user: self.soda_user.or(other.soda_user),
+            soda,
         }
     }
 }
@@ -29,8 +82,8 @@ impl fmt::Display for DesiredCapabilities {
         write!(
             f,
             "(browser: {}, platform: {}, user: {})",
-            self.browser_name.clone().unwrap_or_else(|| "".to_string()),
-            self.platform.clone().unwrap_or_else(|| "".to_string()),
+            self.browser_name.clone().unwrap_or_default(),
+            self.platform.clone().unwrap_or_default(),
             self.soda_user
                 .clone()
                 .unwrap_or_else(|| "GUEST".to_string())
@@ -38,10 +91,128 @@ impl fmt::Display for DesiredCapabilities {
     }
 }
 
+impl W3cCapabilities {
+    fn is_empty(&self) -> bool {
+        self.always_match.is_none() && self.first_match.is_none()
+    }
+
+    /// Merge `alwaysMatch` with each `firstMatch` entry like the W3C spec
+    /// does ("processing capabilities"). An entry redefining a key of
+    /// `alwaysMatch` is invalid and skipped. Without any `firstMatch`,
+    /// `alwaysMatch` alone is used.
+    pub fn merged(&self) -> Vec<Map<String, Value>> {
+        let always_match = self.always_match.clone().unwrap_or_default();
+        let first_match = match &self.first_match {
+            Some(entries) if !entries.is_empty() => entries.to_owned(),
+            _ => vec![Map::new()],
+        };
+
+        first_match
+            .into_iter()
+            .filter(|entry| entry.keys().all(|key| !always_match.contains_key(key)))
+            .map(|entry| {
+                let mut merged = always_match.clone();
+                merged.extend(entry);
+                merged
+            })
+            .collect()
+    }
+}
+
 impl Capabilities {
     pub fn new() -> Capabilities {
-        Capabilities {
-            desired_capabilities: DesiredCapabilities::new(),
-        }
+        Capabilities::default()
+    }
+
+    /// Resolve the capabilities asked by the client.
+    /// The hub picks the first W3C merged entry it can satisfy, we can't
+    /// know which one so we keep the first valid one. The legacy
+    /// `desiredCapabilities` fill in what the W3C envelope doesn't provide.
+    pub fn desired(&self) -> DesiredCapabilities {
+        let w3c = self
+            .capabilities
+            .as_ref()
+            .filter(|capabilities| !capabilities.is_empty())
+            .and_then(|capabilities| capabilities.merged().into_iter().next())
+            .map(|merged| DesiredCapabilities::from_map(&merged))
+            .unwrap_or_default();
+
+        let legacy = self
+            .desired_capabilities
+            .as_ref()
+            .map(DesiredCapabilities::from_map)
+            .unwrap_or_default();
+
+        w3c.or(legacy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capabilities_of(body: &str) -> Capabilities {
+        serde_json::from_str(body).unwrap()
+    }
+
+    #[test]
+    fn desired_merges_always_match_with_the_first_match_entry() {
+        let capabilities = capabilities_of(
+            r#"{
+                "capabilities": {
+                    "alwaysMatch": { "soda:user": "team-a", "platformName": "linux" },
+                    "firstMatch": [{ "browserName": "firefox", "browserVersion": "84.0" }]
+                }
+            }"#,
+        );
+
+        let desired = capabilities.desired();
+
+        assert_eq!(desired.browser_name, Some("firefox".to_string()));
+        assert_eq!(desired.browser_version, Some("84.0".to_string()));
+        assert_eq!(desired.platform, Some("linux".to_string()));
+        assert_eq!(desired.soda_user, Some("team-a".to_string()));
+        assert_eq!(desired.soda.get("soda:user"), Some(&"team-a".to_string()));
+    }
+
+    #[test]
+    fn merged_skips_first_match_entries_redefining_always_match_keys() {
+        let capabilities = capabilities_of(
+            r#"{
+                "capabilities": {
+                    "alwaysMatch": { "browserName": "chrome" },
+                    "firstMatch": [{ "browserName": "firefox" }, { "platformName": "windows" }]
+                }
+            }"#,
+        );
+
+        let merged = capabilities.capabilities.unwrap().merged();
+
+        assert_eq!(merged.len(), 1);
+        assert_eq!(merged[0]["browserName"], "chrome");
+        assert_eq!(merged[0]["platformName"], "windows");
+    }
+
+    #[test]
+    fn desired_falls_back_to_the_legacy_desired_capabilities() {
+        let capabilities = capabilities_of(
+            r#"{
+                "capabilities": { "desiredCapabilities": { "browserName": "chrome" } },
+                "desiredCapabilities": {
+                    "browserName": "chrome",
+                    "version": "87",
+                    "platform": "LINUX",
+                    "soda:team": "payments"
+                }
+            }"#,
+        );
+
+        let desired = capabilities.desired();
+
+        assert_eq!(desired.browser_name, Some("chrome".to_string()));
+        assert_eq!(desired.browser_version, Some("87".to_string()));
+        assert_eq!(desired.platform, Some("LINUX".to_string()));
+        assert_eq!(desired.soda_user, None);
+        assert_eq!(desired.soda.get("soda:team"), Some(&"payments".to_string()));
     }
 }
@@ -11,6 +11,6 @@ impl Command {
     }
 
     pub fn url(self) -> String {
-        self.url.unwrap_or_else(|| "".to_string())
+        self.url.unwrap_or_default()
     }
 }
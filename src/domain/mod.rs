@@ -1,7 +1,9 @@
 mod capabilities;
 mod command;
+mod response;
 pub mod session;
 
 pub use self::capabilities::{Capabilities, DesiredCapabilities};
 pub use self::command::Command;
-pub use self::session::SessionStatus;
+pub use self::response::{CommandResponse, NewSessionOutcome, NewSessionResponse};
+pub use self::session::{Session, SessionEvent, SessionStatus};
@@ -0,0 +1,231 @@
+use crate::domain::DesiredCapabilities;
+use serde_json::Value;
+
+/// Response of the hub to a new session request.
+/// JSONWP hubs answer `{"sessionId": .., "status": 0, "value": {capabilities}}`
+/// while W3C hubs answer `{"value": {"sessionId": .., "capabilities": {..}}}`.
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewSessionResponse {
+    pub session_id: Option<String>,
+    pub status: Option<i64>,
+    pub value: Option<Value>,
+}
+
+/// What the hub decided for a new session request.
+#[derive(PartialEq, Debug)]
+pub enum NewSessionOutcome {
+    Created {
+        session_id: String,
+        capabilities: DesiredCapabilities,
+    },
+    Rejected {
+        error: String,
+        message: String,
+    },
+}
+
+impl NewSessionResponse {
+    pub fn outcome(&self) -> NewSessionOutcome {
+        let value = self.value.as_ref().and_then(Value::as_object);
+        let string_of = |key: &str| {
+            value
+                .and_then(|v| v.get(key))
+                .and_then(Value::as_str)
+                .map(String::from)
+        };
+
+        // A W3C error is an object with an `error` code.
+        if let Some(error) = string_of("error") {
+            return NewSessionOutcome::Rejected {
+                error,
+                message: string_of("message").unwrap_or_default(),
+            };
+        }
+
+        // A JSONWP error has a non zero status.
+        if let Some(status) = self.status.filter(|status| *status != 0) {
+            return NewSessionOutcome::Rejected {
+                error: jsonwp_error_code(status),
+                message: string_of("message").unwrap_or_default(),
+            };
+        }
+
+        let w3c_session_id = string_of("sessionId");
+        let session_id = match w3c_session_id.as_ref().or(self.session_id.as_ref()) {
+            Some(session_id) => session_id.to_owned(),
+            None => {
+                return NewSessionOutcome::Rejected {
+                    error: "unknown error".to_string(),
+                    message: "the hub response doesn't contain any session id".to_string(),
+                }
+            }
+        };
+
+        // The W3C capabilities are nested, the JSONWP ones are the value itself.
+        let capabilities = match w3c_session_id {
+            Some(_) => value
+                .and_then(|v| v.get("capabilities"))
+                .and_then(Value::as_object),
+            None => value,
+        };
+
+        NewSessionOutcome::Created {
+            session_id,
+            capabilities: capabilities
+                .map(DesiredCapabilities::from_map)
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Response of the hub to any command, only read to find its error.
+/// JSONWP hubs answer `{"status": 7, "value": {"message": ..}}` while W3C
+/// hubs answer `{"value": {"error": "no such element", "message": ..}}`.
+#[derive(Default, Deserialize)]
+pub struct CommandResponse {
+    pub status: Option<i64>,
+    pub value: Option<Value>,
+}
+
+impl CommandResponse {
+    /// The WebDriver error code, none when the command succeeded.
+    pub fn error(&self) -> Option<String> {
+        let w3c_error = self
+            .value
+            .as_ref()
+            .and_then(|value| value.get("error"))
+            .and_then(Value::as_str);
+
+        match w3c_error {
+            Some(error) => Some(error.to_string()),
+            None => self
+                .status
+                .filter(|status| *status != 0)
+                .map(jsonwp_error_code),
+        }
+    }
+}
+
+/// Translate the JSONWP numeric status to the W3C error code.
+fn jsonwp_error_code(status: i64) -> String {
+    let code = match status {
+        6 => "invalid session id",
+        7 => "no such element",
+        8 => "no such frame",
+        9 => "unknown command",
+        10 => "stale element reference",
+        11 => "element not interactable",
+        12 => "invalid element state",
+        13 => "unknown error",
+        17 => "javascript error",
+        21 => "timeout",
+        23 => "no such window",
+        24 => "invalid cookie domain",
+        26 => "unexpected alert open",
+        27 => "no such alert",
+        28 => "script timeout",
+        32 => "invalid selector",
+        33 => "session not created",
+        34 => "move target out of bounds",
+        _ => return format!("status {}", status),
+    };
+
+    code.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outcome_of(body: &str) -> NewSessionOutcome {
+        serde_json::from_str::<NewSessionResponse>(body)
+            .unwrap()
+            .outcome()
+    }
+
+    #[test]
+    fn outcome_reads_the_w3c_session_id_and_capabilities() {
+        let outcome = outcome_of(
+            r#"{"value":{"sessionId":"abc","capabilities":{"browserName":"firefox","platformName":"linux"}}}"#,
+        );
+
+        match outcome {
+            NewSessionOutcome::Created {
+                session_id,
+                capabilities,
+            } => {
+                assert_eq!(session_id, "abc");
+                assert_eq!(capabilities.browser_name, Some("firefox".to_string()));
+                assert_eq!(capabilities.platform, Some("linux".to_string()));
+            }
+            _ => panic!("the session should be created"),
+        }
+    }
+
+    #[test]
+    fn outcome_reads_the_jsonwp_session_id_and_capabilities() {
+        let outcome = outcome_of(
+            r#"{"sessionId":"123","status":0,"value":{"browserName":"chrome","platform":"LINUX"}}"#,
+        );
+
+        match outcome {
+            NewSessionOutcome::Created {
+                session_id,
+                capabilities,
+            } => {
+                assert_eq!(session_id, "123");
+                assert_eq!(capabilities.browser_name, Some("chrome".to_string()));
+            }
+            _ => panic!("the session should be created"),
+        }
+    }
+
+    #[test]
+    fn outcome_returns_the_w3c_error_when_the_session_is_rejected() {
+        let outcome = outcome_of(
+            r#"{"value":{"error":"session not created","message":"no such browser","stacktrace":""}}"#,
+        );
+
+        assert_eq!(
+            outcome,
+            NewSessionOutcome::Rejected {
+                error: "session not created".to_string(),
+                message: "no such browser".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn outcome_returns_the_jsonwp_error_when_the_status_is_not_zero() {
+        let outcome = outcome_of(r#"{"status":33,"value":{"message":"grid is full"}}"#);
+
+        assert_eq!(
+            outcome,
+            NewSessionOutcome::Rejected {
+                error: "session not created".to_string(),
+                message: "grid is full".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn error_reads_the_w3c_and_jsonwp_error_codes() {
+        let error_of = |body: &str| {
+            serde_json::from_str::<CommandResponse>(body)
+                .unwrap()
+                .error()
+        };
+
+        assert_eq!(
+            error_of(r#"{"value":{"error":"no such element","message":"login"}}"#),
+            Some("no such element".to_string())
+        );
+        assert_eq!(
+            error_of(r#"{"status":10,"value":{"message":"stale"}}"#),
+            Some("stale element reference".to_string())
+        );
+        assert_eq!(error_of(r#"{"status":0,"value":"title"}"#), None);
+        assert_eq!(error_of(r#"{"value":{"error-free":true}}"#), None);
+    }
+}
@@ -1,18 +1,113 @@
+use crate::domain::DesiredCapabilities;
+use chrono::{DateTime, Utc};
 use std::fmt;
+use std::str::FromStr;
+use uuid::Uuid;
 
-#[derive(PartialEq)]
+/// Where a session is in its lifecycle, as tracked by the registry.
+#[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum SessionStatus {
+    Queued,
     Creating,
+    Active,
+    Deleted,
+    Failed,
+    Orphaned,
+}
+
+impl SessionStatus {
+    /// A finished session will not receive any command anymore.
+    pub fn is_finished(self) -> bool {
+        matches!(
+            self,
+            SessionStatus::Deleted | SessionStatus::Failed | SessionStatus::Orphaned
+        )
+    }
+}
+
+impl FromStr for SessionStatus {
+    type Err = String;
+
+    /// Parse the snake case name of a status, as serialized.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "queued" => Ok(SessionStatus::Queued),
+            "creating" => Ok(SessionStatus::Creating),
+            "active" => Ok(SessionStatus::Active),
+            "deleted" => Ok(SessionStatus::Deleted),
+            "failed" => Ok(SessionStatus::Failed),
+            "orphaned" => Ok(SessionStatus::Orphaned),
+            _ => Err(format!("Unknown session status : {}", s)),
+        }
+    }
+}
+
+/// What happened to a session, the name of its events in the logs.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum SessionEvent {
+    Queued,
+    Creating,
+    Created,
     UrlCommand,
     Deleting,
+    Failed,
+    Orphaned,
 }
 
-impl fmt::Display for SessionStatus {
+impl fmt::Display for SessionEvent {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            SessionStatus::Creating => write!(f, "SESSION_CREATING"),
-            SessionStatus::UrlCommand => write!(f, "SESSION_URL_COMMAND"),
-            SessionStatus::Deleting => write!(f, "SESSION_DELETING"),
+            SessionEvent::Queued => write!(f, "SESSION_QUEUED"),
+            SessionEvent::Creating => write!(f, "SESSION_CREATING"),
+            SessionEvent::Created => write!(f, "SESSION_CREATED"),
+            SessionEvent::UrlCommand => write!(f, "SESSION_URL_COMMAND"),
+            SessionEvent::Deleting => write!(f, "SESSION_DELETING"),
+            SessionEvent::Failed => write!(f, "SESSION_FAILED"),
+            SessionEvent::Orphaned => write!(f, "SESSION_ORPHANED"),
+        }
+    }
+}
+
+/// A test session seen by the proxy, from its creation request to its end.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Session {
+    /// The session id given by the hub, or the request id while the
+    /// session is not created yet.
+    pub id: String,
+    pub request_id: Uuid,
+    pub owner: String,
+    pub capabilities: DesiredCapabilities,
+    /// Address of the hub which created the session.
+    pub hub: Option<String>,
+    pub node: Option<String>,
+    pub start_time: DateTime<Utc>,
+    pub last_activity: DateTime<Utc>,
+    pub command_count: u64,
+    pub last_url: Option<String>,
+    pub status: SessionStatus,
+}
+
+impl Session {
+    pub fn new(id: String, request_id: Uuid, capabilities: DesiredCapabilities) -> Session {
+        let now = Utc::now();
+
+        Session {
+            id,
+            request_id,
+            owner: capabilities
+                .soda_user
+                .clone()
+                .unwrap_or_else(|| "GUEST".to_string()),
+            capabilities,
+            hub: None,
+            node: None,
+            start_time: now,
+            last_activity: now,
+            command_count: 0,
+            last_url: None,
+            status: SessionStatus::Queued,
         }
     }
 }
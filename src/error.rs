@@ -0,0 +1,123 @@
+use crate::timeouts::AppliedTimeout;
+use crate::webdriver;
+use hyper::{Body, Response, StatusCode};
+use std::fmt;
+
+/// A failure of the proxy while forwarding a request to the hub. Every
+/// failure is answered with a WebDriver error, the client connection is
+/// never dropped.
+#[derive(Debug, PartialEq)]
+pub enum ProxyError {
+    /// The URL of the request to the hub can't be built from the client request.
+    InvalidUrl(String),
+    /// The hub can't be reached, e.g. it's down or restarting.
+    HubUnreachable(String),
+    /// The hub didn't answer before the timeout.
+    Timeout(String),
+    /// The hub closed the connection in the middle of its response.
+    TruncatedBody(String),
+}
+
+impl ProxyError {
+    /// Classify an error of the http client while sending a request to `hub`.
+    pub fn of_request(
+        hub: &str,
+        err: &reqwest::Error,
+        timeout: Option<&AppliedTimeout>,
+    ) -> ProxyError {
+        match (err.is_timeout(), timeout) {
+            (true, Some(timeout)) => ProxyError::Timeout(format!(
+                "The hub {} didn't answer within the timeout of {} : {}",
+                hub, timeout, err
+            )),
+            (true, None) => {
+                ProxyError::Timeout(format!("The hub {} didn't answer in time : {}", hub, err))
+            }
+            (false, _) => {
+                ProxyError::HubUnreachable(format!("The hub {} is unreachable : {}", hub, err))
+            }
+        }
+    }
+
+    /// Classify an error of the http client while reading a response of `hub`.
+    pub fn of_response(
+        hub: &str,
+        err: &reqwest::Error,
+        timeout: Option<&AppliedTimeout>,
+    ) -> ProxyError {
+        match (err.is_timeout(), timeout) {
+            (true, Some(timeout)) => ProxyError::Timeout(format!(
+                "The hub {} didn't send its whole response within the timeout of {} : {}",
+                hub, timeout, err
+            )),
+            (true, None) => ProxyError::Timeout(format!(
+                "The hub {} didn't send its whole response in time : {}",
+                hub, err
+            )),
+            (false, _) => ProxyError::TruncatedBody(format!(
+                "The response of the hub {} is truncated : {}",
+                hub, err
+            )),
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ProxyError::InvalidUrl(_) => StatusCode::BAD_REQUEST,
+            ProxyError::HubUnreachable(_) | ProxyError::TruncatedBody(_) => StatusCode::BAD_GATEWAY,
+            ProxyError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+
+    /// WebDriver error code of the response.
+    pub fn error(&self) -> &'static str {
+        match self {
+            ProxyError::InvalidUrl(_) => webdriver::INVALID_ARGUMENT,
+            ProxyError::Timeout(_) => webdriver::TIMEOUT,
+            ProxyError::HubUnreachable(_) | ProxyError::TruncatedBody(_) => {
+                webdriver::UNKNOWN_ERROR
+            }
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            ProxyError::InvalidUrl(message)
+            | ProxyError::HubUnreachable(message)
+            | ProxyError::Timeout(message)
+            | ProxyError::TruncatedBody(message) => message,
+        }
+    }
+
+    pub fn to_response(&self) -> Response<Body> {
+        webdriver::error_response(self.status(), self.error(), self.message())
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_error_has_its_status_and_webdriver_code() {
+        let invalid = ProxyError::InvalidUrl("bad url".to_string());
+        let unreachable = ProxyError::HubUnreachable("connection refused".to_string());
+        let timeout = ProxyError::Timeout("too slow".to_string());
+        let truncated = ProxyError::TruncatedBody("connection closed".to_string());
+
+        assert_eq!(invalid.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(invalid.error(), webdriver::INVALID_ARGUMENT);
+        assert_eq!(unreachable.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(unreachable.error(), webdriver::UNKNOWN_ERROR);
+        assert_eq!(timeout.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(timeout.error(), webdriver::TIMEOUT);
+        assert_eq!(truncated.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(truncated.message(), "connection closed");
+    }
+}
@@ -1,29 +1,75 @@
+use crate::auth;
+use crate::commands::CommandEvent;
 use crate::domain;
+use crate::logging::{self, Event, Outcome};
 use crate::reverse_proxy;
+use crate::webdriver::{self, Route};
+use crate::AppState;
 use bytes::Bytes;
-use hyper::Method;
+use hyper::{Method, StatusCode};
+use log::Level;
 use std::fmt;
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Serialize)]
 struct CreateEvent {
-    event: domain::session::SessionStatus,
+    #[serde(skip)]
+    event: domain::session::SessionEvent,
+    #[serde(rename = "capabilities")]
     desired_capabilities: domain::DesiredCapabilities,
 }
 
-#[derive(PartialEq)]
-struct CommandEvent {
-    event: domain::session::SessionStatus,
+#[derive(PartialEq, Serialize)]
+struct CreatedEvent {
+    #[serde(skip)]
+    event: domain::session::SessionEvent,
+    #[serde(skip)]
+    session_id: String,
+    capabilities: domain::DesiredCapabilities,
+}
+
+#[derive(PartialEq, Serialize)]
+struct FailedEvent {
+    #[serde(skip)]
+    event: domain::session::SessionEvent,
+    error: String,
+    message: String,
+}
+
+#[derive(PartialEq, Serialize)]
+struct UrlEvent {
+    #[serde(skip)]
+    event: domain::session::SessionEvent,
+    #[serde(skip)]
     session_id: String,
     url: String,
 }
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Serialize)]
 struct DeleteEvent {
-    event: domain::session::SessionStatus,
+    #[serde(skip)]
+    event: domain::session::SessionEvent,
+    #[serde(skip)]
     session_id: String,
 }
 
-impl fmt::Display for CommandEvent {
+/// An error of the proxy while forwarding a request to the hub.
+#[derive(Serialize)]
+pub struct ProxyErrorEvent {
+    #[serde(skip)]
+    session_id: Option<String>,
+    message: String,
+}
+
+impl ProxyErrorEvent {
+    pub fn new(path: &str, base_path: &str, message: String) -> ProxyErrorEvent {
+        ProxyErrorEvent {
+            session_id: session_id_of_path(path.to_string(), base_path),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for UrlEvent {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "[{}] [{}] [{}]", self.event, self.session_id, self.url)
     }
@@ -35,34 +81,209 @@ impl fmt::Display for CreateEvent {
     }
 }
 
+impl fmt::Display for CreatedEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[{}] [{}] {}",
+            self.event, self.session_id, self.capabilities
+        )
+    }
+}
+
+impl fmt::Display for FailedEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}] [{}] [{}]", self.event, self.error, self.message)
+    }
+}
+
 impl fmt::Display for DeleteEvent {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "[{}] [{}]", self.event, self.session_id)
     }
 }
 
-pub async fn inspect<'m, 'b>(request: &reverse_proxy::CapturedRequest<'m, 'b>) {
+impl Event for CreateEvent {
+    fn name(&self) -> String {
+        self.event.to_string()
+    }
+}
+
+impl Event for CreatedEvent {
+    fn name(&self) -> String {
+        self.event.to_string()
+    }
+
+    fn session_id(&self) -> Option<&str> {
+        Some(&self.session_id)
+    }
+}
+
+impl Event for FailedEvent {
+    fn name(&self) -> String {
+        self.event.to_string()
+    }
+}
+
+impl Event for UrlEvent {
+    fn name(&self) -> String {
+        self.event.to_string()
+    }
+
+    fn session_id(&self) -> Option<&str> {
+        Some(self.session_id.as_str()).filter(|session_id| !session_id.is_empty())
+    }
+}
+
+impl Event for DeleteEvent {
+    fn name(&self) -> String {
+        self.event.to_string()
+    }
+
+    fn session_id(&self) -> Option<&str> {
+        Some(self.session_id.as_str()).filter(|session_id| !session_id.is_empty())
+    }
+}
+
+impl fmt::Display for ProxyErrorEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[PROXY_ERROR] {}", self.message)
+    }
+}
+
+impl Event for ProxyErrorEvent {
+    fn name(&self) -> String {
+        "PROXY_ERROR".to_string()
+    }
+
+    fn session_id(&self) -> Option<&str> {
+        self.session_id.as_deref()
+    }
+}
+
+pub async fn inspect<'m, 'b>(
+    request: &reverse_proxy::CapturedRequest<'m, 'b>,
+    command: &CommandEvent,
+    state: &AppState,
+) {
+    logging::event(Level::Debug, request, command, None);
+
+    let sessions = &state.sessions;
+    let base_path = &state.settings().base_path;
     let id = request.id.to_owned();
     let method = request.method.to_owned();
     let path = request.path.to_owned();
     let body = request.body.to_owned();
 
     if method == Method::DELETE {
-        info!("{}, Request Id : {}", capture_delete_event(path).await, id);
-    } else if method == Method::POST && is_a_new_session(&path) {
-        info!("{}, Request Id : {}", capture_create_event(&body).await, id);
-    } else if method == "POST" && !is_a_new_session(&path) {
-        if let Some(url_event) = capture_url_event(path, &body) {
-            info!("{}, Request Id : {}", url_event, id);
+        let delete_event = capture_delete_event(path.to_owned(), base_path).await;
+        // Only the session endpoint itself ends the session, not e.g. a cookie deletion
+        if let Route::DeleteSession { .. } = Route::of(&method, &path, base_path) {
+            if let Some(session) = sessions.delete(&delete_event.session_id) {
+                state
+                    .metrics
+                    .session_changed(session.status, &session.capabilities);
+            }
+        } else if !delete_event.session_id.is_empty() {
+            sessions.touch(&delete_event.session_id, id, None);
         }
+        logging::event(Level::Info, request, &delete_event, None);
+    } else if method == Method::POST && is_a_new_session(&path, base_path) {
+        let mut create_event = capture_create_event(&body).await;
+        apply_identity(request, &mut create_event.desired_capabilities, state);
+        sessions.queue(id, create_event.desired_capabilities.clone());
+        logging::event(Level::Info, request, &create_event, None);
+    } else if let Some(session_id) = session_id_of_path(path.to_owned(), base_path) {
+        let url_event = match method {
+            Method::POST => capture_url_event(path, &body, base_path),
+            _ => None,
+        };
+        if let Some(url_event) = &url_event {
+            logging::event(Level::Info, request, url_event, None);
+        }
+        sessions.touch(&session_id, id, url_event.map(|url_event| url_event.url));
+    }
+}
+
+/// The authenticated user replaces `soda:user`. When the identity is only
+/// validated, a missing `soda:user` is filled and another one is kept, the
+/// session is then refused before being forwarded.
+fn apply_identity<'m, 'b>(
+    request: &reverse_proxy::CapturedRequest<'m, 'b>,
+    capabilities: &mut domain::DesiredCapabilities,
+    state: &AppState,
+) {
+    if let Some(user) = &request.user {
+        if capabilities.soda_user.is_none()
+            || state.settings().auth.mode == auth::IdentityMode::Override
+        {
+            capabilities.soda_user = Some(user.to_owned());
+        }
+    }
+}
+
+/// Inspect the hub response to a new session request in order to
+/// log the session id given by the hub, or the reason of the failure.
+pub fn inspect_new_session<'m, 'b>(
+    request: &reverse_proxy::CapturedRequest<'m, 'b>,
+    outcome: &Outcome,
+    body: &Bytes,
+    state: &AppState,
+) -> Option<String> {
+    let sessions = &state.sessions;
+    match capture_new_session_event(outcome.status, body) {
+        Ok(mut created_event) => {
+            let session = sessions.activate(
+                request.id,
+                &created_event.session_id,
+                created_event.capabilities,
+            );
+            state
+                .metrics
+                .session_changed(session.status, &session.capabilities);
+            // The hub doesn't always send back the vendor capabilities (e.g. soda:user)
+            created_event.capabilities = session.capabilities;
+            logging::event(Level::Info, request, &created_event, Some(outcome));
+            Some(created_event.session_id)
+        }
+        Err(failed_event) => {
+            logging::event(Level::Error, request, &failed_event, Some(outcome));
+            if let Some(session) = sessions.fail(request.id) {
+                state
+                    .metrics
+                    .session_changed(session.status, &session.capabilities);
+            }
+            None
+        }
+    }
+}
+
+/// Refuse a new session request without forwarding it to the hub,
+/// e.g. when the user has reached its quota.
+pub fn reject_new_session<'m, 'b>(
+    request: &reverse_proxy::CapturedRequest<'m, 'b>,
+    message: &str,
+    state: &AppState,
+) {
+    let failed_event = FailedEvent {
+        event: domain::session::SessionEvent::Failed,
+        error: webdriver::SESSION_NOT_CREATED.to_string(),
+        message: message.to_string(),
+    };
+    logging::event(Level::Warn, request, &failed_event, None);
+
+    if let Some(session) = state.sessions.fail(request.id) {
+        state
+            .metrics
+            .session_changed(session.status, &session.capabilities);
     }
 }
 
-async fn capture_delete_event(path: String) -> DeleteEvent {
-    let session_id = session_id_of_path(path).unwrap_or_else(|| "".to_string());
+async fn capture_delete_event(path: String, base_path: &str) -> DeleteEvent {
+    let session_id = session_id_of_path(path, base_path).unwrap_or_default();
 
     DeleteEvent {
-        event: domain::session::SessionStatus::Deleting,
+        event: domain::session::SessionEvent::Deleting,
         session_id,
     }
 }
@@ -78,17 +299,54 @@ async fn capture_create_event(body: &Bytes) -> CreateEvent {
         })
         .unwrap_or_else(|_| domain::Capabilities::new());
 
-    let desired_capabilities = capabilities.desired_capabilities;
+    let desired_capabilities = capabilities.desired();
 
     CreateEvent {
-        event: domain::session::SessionStatus::Creating,
+        event: domain::session::SessionEvent::Creating,
         desired_capabilities,
     }
 }
 
+/// Capture the outcome of a new session request from the hub response
+fn capture_new_session_event(
+    status: StatusCode,
+    body: &Bytes,
+) -> Result<CreatedEvent, FailedEvent> {
+    let response: domain::NewSessionResponse = match serde_json::from_slice(body) {
+        Ok(response) => response,
+        Err(_) => {
+            return Err(FailedEvent {
+                event: domain::session::SessionEvent::Failed,
+                error: webdriver::UNKNOWN_ERROR.to_string(),
+                message: format!(
+                    "unreadable hub response ({}) : {}",
+                    status,
+                    std::str::from_utf8(body).unwrap_or("cannot read the body")
+                ),
+            })
+        }
+    };
+
+    match response.outcome() {
+        domain::NewSessionOutcome::Created {
+            session_id,
+            capabilities,
+        } => Ok(CreatedEvent {
+            event: domain::session::SessionEvent::Created,
+            session_id,
+            capabilities,
+        }),
+        domain::NewSessionOutcome::Rejected { error, message } => Err(FailedEvent {
+            event: domain::session::SessionEvent::Failed,
+            error,
+            message,
+        }),
+    }
+}
+
 /// Capture asked url events
-fn capture_url_event(path: String, body: &Bytes) -> Option<CommandEvent> {
-    if path.contains("/url") {
+fn capture_url_event(path: String, body: &Bytes, base_path: &str) -> Option<UrlEvent> {
+    if CommandEvent::of(&Method::POST, &path, base_path).command == "navigateTo" {
         let command: domain::Command = serde_json::from_slice(body)
             .map_err(|_| {
                 error!(
@@ -98,11 +356,11 @@ fn capture_url_event(path: String, body: &Bytes) -> Option<CommandEvent> {
             })
             .unwrap_or_else(|_| domain::Command::new());
 
-        let session_id = session_id_of_path(path).unwrap_or_else(|| "".to_string());
+        let session_id = session_id_of_path(path, base_path).unwrap_or_default();
 
         // event | session_status | session ID | url_command | url
-        return Some(CommandEvent {
-            event: domain::session::SessionStatus::UrlCommand,
+        return Some(UrlEvent {
+            event: domain::session::SessionEvent::UrlCommand,
             session_id,
             url: command.url(),
         });
@@ -110,62 +368,33 @@ fn capture_url_event(path: String, body: &Bytes) -> Option<CommandEvent> {
 
     None
 }
-/// Split the path to determine if it's a new session
-/// (the path doesn't contain the session's id) or if it's
-/// an existing session (the path contains the session's id).
-/// If the head and the tail of the path are empty,
-/// it's a new session event that we want to capture.
-pub fn is_a_new_session(path: &str) -> bool {
-    let splitted_path: Vec<&str> = path
-        .split("/wd/hub/session")
-        .filter(|item| !item.is_empty())
-        .collect();
-
-    splitted_path.is_empty()
-}
 
-/// Split the given path and try to retrieve the
-/// session id.
-fn session_id_of_path(path: String) -> Option<String> {
-    // we only do the check if the path concerns a valid session endpoint
-    if !path.contains("wd/hub/session") {
-        return None;
-    }
-    // Try to get the session's id part
-    // e.g. possible patterns :
-    // /wd/hub/session
-    // /wd/hub/session/:id
-    // /wd/hub/session/:id/:cmd
-    let tail: Vec<&str> = path
-        .split("/wd/hub/session")
-        .filter(|item| !item.is_empty())
-        .collect();
-
-    // Check if there is a remainder with a session's id
-    // e.g. possible patterns :
-    // /:id
-    // /:id/:cmd
-    if !tail.is_empty() {
-        let remainder: Vec<&str> = tail[0].split('/').filter(|s| !s.is_empty()).collect();
-        if !remainder.is_empty() {
-            return Some(remainder[0].to_string());
-        }
-    }
+/// Whether the path is the endpoint creating the sessions,
+/// with or without the `/wd/hub` prefix or the base path.
+pub fn is_a_new_session(path: &str, base_path: &str) -> bool {
+    Route::of(&Method::POST, path, base_path) == Route::NewSession
+}
 
-    None
+/// The id of the session of a command, none when the path isn't a command
+/// of a session (e.g. a new session or the status).
+pub fn session_id_of_path(path: String, base_path: &str) -> Option<String> {
+    Route::of(&Method::GET, &path, base_path)
+        .session_id()
+        .map(String::from)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::webdriver::DEFAULT_BASE_PATH;
 
     #[tokio::test]
     async fn capture_delete_event_should_return_a_well_formatted_log_with_the_session_id() {
         let path = "/wd/hub/session/123";
-        let delete_event = capture_delete_event(path.to_string()).await;
+        let delete_event = capture_delete_event(path.to_string(), DEFAULT_BASE_PATH).await;
 
         let expected_delete_event = DeleteEvent {
-            event: domain::session::SessionStatus::Deleting,
+            event: domain::session::SessionEvent::Deleting,
             session_id: "123".to_string(),
         };
 
@@ -175,19 +404,22 @@ mod tests {
     #[test]
     fn session_id_of_path_returns_none_when_session_id_is_missing() {
         let path: String = "/wd/hub/session//".to_string();
-        assert!(session_id_of_path(path).is_none());
+        assert!(session_id_of_path(path, DEFAULT_BASE_PATH).is_none());
     }
 
     #[test]
     fn session_id_of_path_returns_some_when_session_id_exists() {
         let path: String = "/wd/hub/session/123/screenshot".to_string();
-        assert_eq!(session_id_of_path(path), Some("123".to_string()));
+        assert_eq!(
+            session_id_of_path(path, DEFAULT_BASE_PATH),
+            Some("123".to_string())
+        );
     }
 
     #[test]
     fn session_id_of_path_returns_none_when_path_is_malformed() {
         let path: String = "/bad/hub/session/123/screenshot".to_string();
-        assert!(session_id_of_path(path).is_none());
+        assert!(session_id_of_path(path, DEFAULT_BASE_PATH).is_none());
     }
 
     #[tokio::test]
@@ -195,8 +427,12 @@ mod tests {
     ) {
         let desired: domain::DesiredCapabilities = domain::DesiredCapabilities {
             browser_name: Some("chrome".to_string()),
+            browser_version: None,
             platform: Some("LINUX".to_string()),
             soda_user: Some("user123".to_string()),
+            soda: vec![("soda:user".to_string(), "user123".to_string())]
+                .into_iter()
+                .collect(),
         };
 
         let mock_post_http_request_body = r#"
@@ -229,7 +465,7 @@ mod tests {
         let create_event = capture_create_event(&body).await;
 
         let expected_create_event = CreateEvent {
-            event: domain::SessionStatus::Creating,
+            event: domain::session::SessionEvent::Creating,
             desired_capabilities: desired,
         };
 
@@ -270,12 +506,16 @@ mod tests {
 
         let desired: domain::DesiredCapabilities = domain::DesiredCapabilities {
             browser_name: Some("".to_string()),
+            browser_version: None,
             platform: Some("".to_string()),
             soda_user: Some("".to_string()),
+            soda: vec![("soda:user".to_string(), "".to_string())]
+                .into_iter()
+                .collect(),
         };
 
         let expected_create_event = CreateEvent {
-            event: domain::SessionStatus::Creating,
+            event: domain::session::SessionEvent::Creating,
             desired_capabilities: desired,
         };
 
@@ -287,10 +527,10 @@ mod tests {
     ) {
         let path = "/bad/path/session/123";
 
-        let delete_event = capture_delete_event(path.to_string()).await;
+        let delete_event = capture_delete_event(path.to_string(), DEFAULT_BASE_PATH).await;
 
         let expected_delete_event = DeleteEvent {
-            event: domain::session::SessionStatus::Deleting,
+            event: domain::session::SessionEvent::Deleting,
             session_id: "".to_string(),
         };
 
@@ -307,13 +547,13 @@ mod tests {
         let body = Bytes::from(mock_post_http_request_body);
         let path = "/wd/hub/session/f52c41e5-3c3f-4cf3-9fe2-963e4a744aa7/url".to_string();
 
-        let expected_event = Some(CommandEvent {
-            event: domain::session::SessionStatus::UrlCommand,
+        let expected_event = Some(UrlEvent {
+            event: domain::session::SessionEvent::UrlCommand,
             session_id: "f52c41e5-3c3f-4cf3-9fe2-963e4a744aa7".to_string(),
             url: "https://duckduckgo.com/".to_string(),
         });
 
-        let capture_event = capture_url_event(path, &body);
+        let capture_event = capture_url_event(path, &body, DEFAULT_BASE_PATH);
 
         assert!(capture_event == expected_event);
     }
@@ -328,7 +568,7 @@ mod tests {
         let body = Bytes::from(mock_post_http_request_body);
         let path = "/wd/hub/session/f52c41e5-3c3f-4cf3-9fe2-963e4a744aa7".to_string();
 
-        let capture_event = capture_url_event(path, &body);
+        let capture_event = capture_url_event(path, &body, DEFAULT_BASE_PATH);
 
         assert!(capture_event.is_none());
     }
@@ -342,35 +582,85 @@ mod tests {
         let body = Bytes::from(mock_post_http_request_body);
         let path = "/wd/hub/session/f52c41e5-3c3f-4cf3-9fe2-963e4a744aa7/url".to_string();
 
-        let expected_event = Some(CommandEvent {
-            event: domain::session::SessionStatus::UrlCommand,
+        let expected_event = Some(UrlEvent {
+            event: domain::session::SessionEvent::UrlCommand,
             session_id: "f52c41e5-3c3f-4cf3-9fe2-963e4a744aa7".to_string(),
             url: "".to_string(),
         });
 
-        let capture_event = capture_url_event(path, &body);
+        let capture_event = capture_url_event(path, &body, DEFAULT_BASE_PATH);
 
         assert!(capture_event == expected_event);
     }
 
+    #[test]
+    fn capture_new_session_event_returns_the_session_id_given_by_the_hub() {
+        let body = Bytes::from(
+            r#"{"value":{"sessionId":"f52c41e5","capabilities":{"browserName":"chrome"}}}"#,
+        );
+
+        let created_event = capture_new_session_event(StatusCode::OK, &body);
+
+        assert!(
+            matches!(created_event, Ok(CreatedEvent { ref session_id, .. }) if session_id == "f52c41e5")
+        );
+    }
+
+    #[test]
+    fn capture_new_session_event_returns_a_failed_event_when_the_hub_rejects_the_session() {
+        let body = Bytes::from(
+            r#"{"value":{"error":"session not created","message":"timed out waiting for a node"}}"#,
+        );
+
+        let failed_event = capture_new_session_event(StatusCode::INTERNAL_SERVER_ERROR, &body);
+
+        let expected_failed_event = FailedEvent {
+            event: domain::session::SessionEvent::Failed,
+            error: "session not created".to_string(),
+            message: "timed out waiting for a node".to_string(),
+        };
+
+        assert!(failed_event.err() == Some(expected_failed_event));
+    }
+
+    #[test]
+    fn capture_new_session_event_returns_a_failed_event_when_the_body_is_not_json() {
+        let body = Bytes::from("Bad Gateway");
+
+        let failed_event = capture_new_session_event(StatusCode::BAD_GATEWAY, &body);
+
+        assert!(
+            matches!(failed_event, Err(FailedEvent { ref error, .. }) if error == "unknown error")
+        );
+    }
+
     #[test]
     fn is_a_new_session_returns_true_when_the_path_does_not_contain_session_id() {
         let path = "/wd/hub/session".to_string();
 
-        assert!(is_a_new_session(&path));
+        assert!(is_a_new_session(&path, DEFAULT_BASE_PATH));
     }
 
     #[test]
     fn is_a_new_session_returns_false_when_there_is_a_session_id() {
         let path = "/wd/hub/session/123/screenshot".to_string();
 
-        assert!(!is_a_new_session(&path));
+        assert!(!is_a_new_session(&path, DEFAULT_BASE_PATH));
     }
 
     #[test]
     fn is_a_new_session_returns_false_when_the_path_is_malformed() {
         let path = "/wd/hub/session//screenshot".to_string();
 
-        assert!(!is_a_new_session(&path));
+        assert!(!is_a_new_session(&path, DEFAULT_BASE_PATH));
+    }
+
+    #[test]
+    fn new_session_and_session_id_are_found_without_the_wd_hub_prefix() {
+        assert!(is_a_new_session("/session", DEFAULT_BASE_PATH));
+        assert_eq!(
+            session_id_of_path("/session/123/url".to_string(), DEFAULT_BASE_PATH),
+            Some("123".to_string())
+        );
     }
 }
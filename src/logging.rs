@@ -0,0 +1,250 @@
+use crate::reverse_proxy::CapturedRequest;
+use chrono::Utc;
+use hyper::StatusCode;
+use log::Level;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Target of the log records carrying an event, it can be used to filter
+/// them with `RUST_LOG`. In JSON, their message is already formatted.
+const EVENT_TARGET: &str = "soda_test_service::events";
+
+static JSON_FORMAT: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LogFormat {
+    /// Human readable lines, e.g. `[SESSION_CREATING] (browser: chrome, ...)`
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format : {}", s)),
+        }
+    }
+}
+
+/// An event about a test session, or about the proxy itself.
+/// The `Display` implementation is used by the text format and the
+/// serialized fields are added to the JSON object by the JSON format.
+pub trait Event: fmt::Display + Serialize {
+    /// Name of the event, e.g. `SESSION_CREATING`
+    fn name(&self) -> String;
+
+    fn session_id(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// Response of the hub related to an event.
+pub struct Outcome {
+    pub status: StatusCode,
+    pub duration: Duration,
+}
+
+/// Filter of the logs, in the `RUST_LOG` syntax : `-v` shows the requests
+/// (debug), `-vv` the debug logs of the libraries too, `-q` only the warnings
+/// and `-qq` only the errors. Without them, `--log-level` then `RUST_LOG` are
+/// used, and the session events are logged at info by default.
+pub fn filter(
+    verbose: u64,
+    quiet: u64,
+    log_level: Option<&str>,
+    rust_log: Option<String>,
+) -> String {
+    match (verbose, quiet) {
+        (0, 0) => log_level
+            .map(String::from)
+            .or(rust_log)
+            .unwrap_or_else(|| "info".to_string()),
+        (1, _) => "info,soda_test_service=debug".to_string(),
+        (_, 0) => "debug".to_string(),
+        (0, 1) => "warn".to_string(),
+        _ => "error".to_string(),
+    }
+}
+
+pub fn init(format: LogFormat, filter: &str) {
+    JSON_FORMAT.store(format == LogFormat::Json, Ordering::SeqCst);
+
+    let mut builder = env_logger::Builder::new();
+    builder.parse_filters(filter);
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            if record.target() == EVENT_TARGET {
+                return writeln!(buf, "{}", record.args());
+            }
+
+            let line = json!({
+                "timestamp": Utc::now(),
+                "level": record.level().to_string(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{}", line)
+        });
+    }
+    builder.init();
+}
+
+/// Log an event related to a request, in the configured format.
+pub fn event<E: Event>(
+    level: Level,
+    request: &CapturedRequest,
+    event: &E,
+    outcome: Option<&Outcome>,
+) {
+    if JSON_FORMAT.load(Ordering::SeqCst) {
+        log!(target: EVENT_TARGET, level, "{}", to_json(Some(request), event, outcome));
+    } else {
+        log!(target: EVENT_TARGET, level, "{}, Request Id : {}", event, request.id);
+    }
+}
+
+/// Log an event raised by the proxy itself, without any client request.
+pub fn proxy_event<E: Event>(level: Level, event: &E) {
+    if JSON_FORMAT.load(Ordering::SeqCst) {
+        log!(target: EVENT_TARGET, level, "{}", to_json(None, event, None));
+    } else {
+        log!(target: EVENT_TARGET, level, "{}", event);
+    }
+}
+
+/// Serialize the event with the fields shared by every event.
+/// Those fields are always present (`null` when unknown) to keep a stable schema.
+fn to_json<E: Event>(
+    request: Option<&CapturedRequest>,
+    event: &E,
+    outcome: Option<&Outcome>,
+) -> Value {
+    let mut object = Map::new();
+    object.insert("timestamp".to_string(), json!(Utc::now()));
+    object.insert("event".to_string(), json!(event.name()));
+    object.insert(
+        "request_id".to_string(),
+        json!(request.map(|request| request.id)),
+    );
+    object.insert("session_id".to_string(), json!(event.session_id()));
+    object.insert(
+        "method".to_string(),
+        json!(request.map(|request| request.method.as_str())),
+    );
+    object.insert(
+        "path".to_string(),
+        json!(request.map(|request| &request.path)),
+    );
+    object.insert(
+        "status".to_string(),
+        json!(outcome.map(|outcome| outcome.status.as_u16())),
+    );
+    object.insert(
+        "duration_ms".to_string(),
+        json!(outcome.map(|outcome| outcome.duration.as_millis() as u64)),
+    );
+
+    if let Ok(Value::Object(fields)) = serde_json::to_value(event) {
+        object.extend(fields);
+    }
+
+    Value::Object(object)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use hyper::Method;
+    use url::Url;
+    use uuid::Uuid;
+
+    #[derive(Serialize)]
+    struct TestEvent {
+        #[serde(skip)]
+        session_id: String,
+        url: String,
+    }
+
+    impl fmt::Display for TestEvent {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "[TEST] [{}]", self.url)
+        }
+    }
+
+    impl Event for TestEvent {
+        fn name(&self) -> String {
+            "TEST".to_string()
+        }
+
+        fn session_id(&self) -> Option<&str> {
+            Some(&self.session_id)
+        }
+    }
+
+    #[test]
+    fn to_json_adds_the_request_fields_to_the_event_fields() {
+        let body = Bytes::new();
+        let request = CapturedRequest {
+            id: Uuid::new_v4(),
+            url: Url::parse("http://localhost:4444/wd/hub/session/123/url").unwrap(),
+            method: &Method::POST,
+            path: "/wd/hub/session/123/url".to_string(),
+            body: &body,
+            user: None,
+        };
+        let event = TestEvent {
+            session_id: "123".to_string(),
+            url: "https://duckduckgo.com/".to_string(),
+        };
+        let outcome = Outcome {
+            status: StatusCode::OK,
+            duration: Duration::from_millis(42),
+        };
+
+        let line = to_json(Some(&request), &event, Some(&outcome));
+
+        assert_eq!(line["event"], "TEST");
+        assert_eq!(line["request_id"], request.id.to_string());
+        assert_eq!(line["session_id"], "123");
+        assert_eq!(line["method"], "POST");
+        assert_eq!(line["path"], "/wd/hub/session/123/url");
+        assert_eq!(line["status"], 200);
+        assert_eq!(line["duration_ms"], 42);
+        assert_eq!(line["url"], "https://duckduckgo.com/");
+        assert!(line["timestamp"].is_string());
+    }
+
+    #[test]
+    fn filter_prefers_the_flags_then_the_log_level_then_rust_log() {
+        let rust_log = || Some("hyper=debug".to_string());
+
+        assert_eq!(filter(0, 0, None, None), "info");
+        assert_eq!(filter(0, 0, None, rust_log()), "hyper=debug");
+        assert_eq!(filter(0, 0, Some("warn"), rust_log()), "warn");
+        assert_eq!(
+            filter(1, 0, Some("warn"), rust_log()),
+            "info,soda_test_service=debug"
+        );
+        assert_eq!(filter(2, 0, None, None), "debug");
+        assert_eq!(filter(0, 1, None, rust_log()), "warn");
+        assert_eq!(filter(0, 2, None, None), "error");
+    }
+
+    #[test]
+    fn log_format_is_parsed_from_the_cli_value() {
+        assert_eq!("json".parse::<LogFormat>(), Ok(LogFormat::Json));
+        assert_eq!("text".parse::<LogFormat>(), Ok(LogFormat::Text));
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+}
@@ -2,57 +2,296 @@
 extern crate serde_derive;
 #[macro_use]
 extern crate log;
-#[macro_use]
-extern crate clap;
 
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request};
 use hyper::{Error, Server};
 use reqwest::Client as HttpClient;
-use std::net::ToSocketAddrs;
-
+use std::env;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time;
 
+mod admin;
+mod auth;
+mod balancing;
+mod body;
 mod cli;
+mod commands;
+mod config;
 mod domain;
+mod error;
 mod inspector;
+mod logging;
+mod metrics;
+mod queue;
+mod quotas;
+mod reaper;
+mod registry;
+mod retries;
 mod reverse_proxy;
+mod routing;
+mod screenshots;
+mod shutdown;
+mod timeline;
+mod timeouts;
+mod webdriver;
+
+/// Idle connections kept by hub, and for how long (in seconds).
+const DEFAULT_POOL_MAX_IDLE: usize = 32;
+const DEFAULT_POOL_IDLE_TIMEOUT: u64 = 90;
 
 pub struct AppState {
     pub client: HttpClient,
-    pub forward_uri: String,
+    settings: RwLock<Arc<config::Settings>>,
+    pub sessions: registry::SessionRegistry,
+    pub metrics: metrics::Metrics,
+    pub queue: queue::SessionQueue,
+    pub timelines: timeline::Timelines,
+    pub screenshots: screenshots::Screenshots,
+    shutting_down: AtomicBool,
+}
+
+impl AppState {
+    pub fn new(forward_uri: String, timeout: u32) -> AppState {
+        AppState::with_settings(config::Settings::new(forward_uri, timeout))
+    }
+
+    pub fn with_settings(settings: config::Settings) -> AppState {
+        AppState {
+            client: reverse_proxy::pooled_client(
+                DEFAULT_POOL_MAX_IDLE,
+                Duration::from_secs(DEFAULT_POOL_IDLE_TIMEOUT),
+            ),
+            sessions: registry::SessionRegistry::new(),
+            metrics: metrics::Metrics::new(),
+            queue: queue::SessionQueue::new(settings.grid_capacity, settings.queue_timeout),
+            timelines: timeline::Timelines::new(),
+            screenshots: screenshots::Screenshots::new(),
+            settings: RwLock::new(Arc::new(settings)),
+            shutting_down: AtomicBool::new(false),
+        }
+    }
+
+    /// The current settings, a request keeps them until its end even when
+    /// the configuration is reloaded in the meantime.
+    pub fn settings(&self) -> Arc<config::Settings> {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// Apply a reloaded configuration, the sessions and the queue are kept.
+    pub fn set_settings(&self, settings: config::Settings) {
+        settings.router.keep_health_of(&self.settings().router);
+        self.queue
+            .set_limits(settings.grid_capacity, settings.queue_timeout);
+        *self.settings.write().unwrap() = Arc::new(settings);
+        // The capacity of the grid may be higher
+        self.queue.dispatch(&self.sessions);
+    }
+
+    /// Refuse the new sessions, the queued ones included, while the
+    /// requests in flight are drained.
+    pub fn stop_accepting_sessions(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        self.queue.close();
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
 }
 
 #[tokio::main]
 async fn main() {
-    std::env::set_var("RUST_LOG", "info");
-    env_logger::init();
     let matches = cli::init();
 
-    // Configure addresses to listen and forward.
-    let listen = matches.value_of("listen").unwrap();
-    let forwarded = matches.value_of("forward").unwrap();
-    let in_addr = listen.to_socket_addrs().unwrap().next().unwrap();
-    let forward_str = forwarded.to_socket_addrs().unwrap().next().unwrap();
-    // Used to give a more verbose output. (all info logs)
-    let _verbose = matches.occurrences_of("verbose");
-
-    // Configure the timeout for the proxy, default to 60s
-    let timeout = value_t!(matches, "timeout", u32).unwrap_or(60);
-
-    let make_svc = make_service_fn(move |_| async move {
-        Ok::<_, Error>(service_fn(move |req: Request<Body>| async move {
-            reverse_proxy::forward(req, forward_str, timeout).await
-        }))
+    // The command line, the environment variables and the configuration file
+    let config = config::Config::load(&matches).unwrap_or_else(|err| {
+        eprintln!("Invalid configuration : {}", err);
+        process::exit(1)
     });
+    let log_format = config
+        .parse::<logging::LogFormat>("log-format")
+        .unwrap_or_else(|err| {
+            eprintln!("Invalid configuration : {}", err);
+            process::exit(1)
+        })
+        .unwrap_or(logging::LogFormat::Text);
+    logging::init(
+        log_format,
+        &logging::filter(
+            matches.occurrences_of("verbose"),
+            matches.occurrences_of("quiet"),
+            config.value("log-level"),
+            env::var("RUST_LOG").ok(),
+        ),
+    );
 
-    let server = Server::bind(&in_addr).serve(make_svc);
+    let settings = config::Settings::from_config(&config).unwrap_or_else(|err| {
+        error!("Invalid configuration : {}", err);
+        process::exit(1)
+    });
+    let startup = read_startup_settings(&config).unwrap_or_else(|err| {
+        error!("Invalid configuration : {}", err);
+        process::exit(1)
+    });
+
+    let mut state = AppState::with_settings(settings);
+    // Keep the connections to the hubs alive between the requests
+    state.client = reverse_proxy::pooled_client(startup.pool_max_idle, startup.pool_idle_timeout);
+    let state = Arc::new(state);
+
+    // Forward the commands of the sessions opened through the previous proxy
+    match shutdown::take_over(&state, &startup.handoff_file) {
+        Ok(0) => {}
+        Ok(count) => info!("{} sessions handed off by the previous proxy", count),
+        Err(err) => error!("The handed off sessions can't be taken over : {}", err),
+    }
+
+    // Stop sending new sessions to the hubs which are down
+    tokio::spawn(balancing::run_health_checks(
+        state.clone(),
+        startup.health_check_interval,
+    ));
+
+    // Delete the screenshots once they expired
+    tokio::spawn(screenshots::run_cleanup(state.clone()));
+
+    // Reclaim the browsers of the sessions abandoned by their clients
+    if let Some(idle_timeout) = startup.idle_timeout {
+        tokio::spawn(reaper::run(state.clone(), idle_timeout));
+    }
 
     info!(
-        "Server will listen on {} and forward to {}",
-        listen, forwarded
+        "Server will listen on {} and forward to {:?} ({})",
+        startup.listen,
+        config
+            .parse_all::<balancing::HubAddress>("forward")
+            .unwrap_or_default(),
+        config.value("balancing").unwrap_or_default()
     );
-
-    if let Err(e) = server.await {
-        error!("server error: {}", e);
+    if let Some(file) = config.file() {
+        info!(
+            "The configuration is reloaded on SIGHUP or when {} changes",
+            file
+        );
     }
+    tokio::spawn(config::run_reloads(state.clone(), matches.clone(), config));
+
+    let service_state = state.clone();
+    let make_svc = make_service_fn(move |_| {
+        let state = service_state.clone();
+        async move {
+            Ok::<_, Error>(service_fn(move |req: Request<Body>| {
+                let state = state.clone();
+                async move {
+                    // The admin API and the metrics are served by the proxy,
+                    // everything else goes to the hub
+                    if admin::is_an_admin_path(req.uri().path()) {
+                        return Ok(admin::handle(&req, &state).await);
+                    }
+                    reverse_proxy::forward(req, state).await
+                }
+            }))
+        }
+    });
+
+    // On SIGTERM or SIGINT, stop accepting connections and new sessions
+    // but let the requests in flight finish, until the deadline
+    let (stop, stopped) = oneshot::channel::<()>();
+    let server = Server::bind(&startup.listen)
+        .serve(make_svc)
+        .with_graceful_shutdown(async {
+            let _ = stopped.await;
+        });
+    tokio::pin!(server);
+
+    let signal = tokio::select! {
+        result = &mut server => {
+            if let Err(e) = result {
+                error!("server error: {}", e);
+            }
+            return;
+        }
+        signal = shutdown::signal_received() => signal,
+    };
+
+    info!(
+        "{} received, waiting {}s at most for {} requests in flight",
+        signal,
+        startup.shutdown_timeout.as_secs(),
+        state.metrics.requests_in_flight()
+    );
+    state.stop_accepting_sessions();
+    let _ = stop.send(());
+
+    let drained = match time::timeout(startup.shutdown_timeout, server).await {
+        Ok(Ok(())) => true,
+        Ok(Err(e)) => {
+            error!("server error: {}", e);
+            true
+        }
+        Err(_) => false,
+    };
+
+    shutdown::close_sessions(
+        &state,
+        signal,
+        drained,
+        startup.shutdown_sessions,
+        &startup.handoff_file,
+    )
+    .await;
+}
+
+/// The settings which are only read at startup.
+struct StartupSettings {
+    listen: SocketAddr,
+    pool_max_idle: usize,
+    pool_idle_timeout: Duration,
+    health_check_interval: Duration,
+    idle_timeout: Option<Duration>,
+    shutdown_timeout: Duration,
+    shutdown_sessions: shutdown::OpenSessions,
+    handoff_file: String,
+}
+
+fn read_startup_settings(config: &config::Config) -> Result<StartupSettings, String> {
+    let listen = config.value("listen").unwrap_or_default();
+
+    Ok(StartupSettings {
+        listen: listen
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addresses| addresses.next())
+            .ok_or_else(|| format!("The address {} can't be listened", listen))?,
+        pool_max_idle: config
+            .parse::<usize>("pool-max-idle")?
+            .unwrap_or(DEFAULT_POOL_MAX_IDLE),
+        pool_idle_timeout: Duration::from_secs(
+            config
+                .parse::<u64>("pool-idle-timeout")?
+                .unwrap_or(DEFAULT_POOL_IDLE_TIMEOUT),
+        ),
+        health_check_interval: Duration::from_secs(
+            config.parse::<u64>("health-check-interval")?.unwrap_or(10),
+        ),
+        idle_timeout: config
+            .parse::<u64>("idle-timeout")?
+            .map(Duration::from_secs),
+        shutdown_timeout: Duration::from_secs(
+            config.parse::<u64>("shutdown-timeout")?.unwrap_or(30),
+        ),
+        shutdown_sessions: config
+            .parse::<shutdown::OpenSessions>("shutdown-sessions")?
+            .unwrap_or(shutdown::OpenSessions::Keep),
+        handoff_file: config
+            .value("handoff-file")
+            .unwrap_or("soda-sessions.json")
+            .to_string(),
+    })
 }
@@ -0,0 +1,393 @@
+use crate::domain::{DesiredCapabilities, SessionStatus};
+use crate::registry::SessionRegistry;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds (in seconds) of the request duration histogram buckets.
+/// WebDriver commands go from a few milliseconds to minutes (session creation).
+const DURATION_BUCKETS: [f64; 13] = [
+    0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0,
+];
+
+/// A counter split by a fixed set of labels.
+struct Counter {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, u64>>,
+}
+
+impl Counter {
+    fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Counter {
+        Counter {
+            name,
+            help,
+            labels,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn inc(&self, label_values: &[&str]) {
+        let key = label_values.iter().map(|value| value.to_string()).collect();
+        *self.values.lock().unwrap().entry(key).or_insert(0) += 1;
+    }
+
+    fn render(&self, out: &mut String) {
+        header(out, self.name, self.help, "counter");
+        for (label_values, value) in self.values.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "{}{} {}",
+                self.name,
+                labels(self.labels, label_values, None),
+                value
+            );
+        }
+    }
+}
+
+#[derive(Default)]
+struct HistogramValue {
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+/// A histogram split by a fixed set of labels, using `DURATION_BUCKETS`.
+struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, HistogramValue>>,
+}
+
+impl Histogram {
+    fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Histogram {
+        Histogram {
+            name,
+            help,
+            labels,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn observe(&self, label_values: &[&str], seconds: f64) {
+        let key = label_values.iter().map(|value| value.to_string()).collect();
+        let mut values = self.values.lock().unwrap();
+        let value = values.entry(key).or_insert_with(|| HistogramValue {
+            buckets: vec![0; DURATION_BUCKETS.len()],
+            ..HistogramValue::default()
+        });
+
+        for (bucket, bound) in value.buckets.iter_mut().zip(DURATION_BUCKETS.iter()) {
+            if seconds <= *bound {
+                *bucket += 1;
+            }
+        }
+        value.sum += seconds;
+        value.count += 1;
+    }
+
+    fn render(&self, out: &mut String) {
+        header(out, self.name, self.help, "histogram");
+        for (label_values, value) in self.values.lock().unwrap().iter() {
+            for (bucket, bound) in value.buckets.iter().zip(DURATION_BUCKETS.iter()) {
+                let le = bound.to_string();
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{} {}",
+                    self.name,
+                    labels(self.labels, label_values, Some(&le)),
+                    bucket
+                );
+            }
+            let _ = writeln!(
+                out,
+                "{}_bucket{} {}",
+                self.name,
+                labels(self.labels, label_values, Some("+Inf")),
+                value.count
+            );
+            let label_set = labels(self.labels, label_values, None);
+            let _ = writeln!(out, "{}_sum{} {}", self.name, label_set, value.sum);
+            let _ = writeln!(out, "{}_count{} {}", self.name, label_set, value.count);
+        }
+    }
+}
+
+/// Decrease the in flight requests gauge when the request is over,
+/// whatever the way it ends.
+pub struct InFlightGuard<'a> {
+    gauge: &'a AtomicI64,
+}
+
+impl<'a> Drop for InFlightGuard<'a> {
+    fn drop(&mut self) {
+        self.gauge.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Metrics of the proxy and of the test sessions, exposed in the
+/// Prometheus text format on `/metrics`.
+pub struct Metrics {
+    sessions_created: Counter,
+    sessions_deleted: Counter,
+    sessions_failed: Counter,
+    sessions_reclaimed: Counter,
+    request_duration: Histogram,
+    hub_server_errors: Counter,
+    retries: Counter,
+    in_flight: AtomicI64,
+}
+
+const SESSION_LABELS: &[&str] = &["browser_name", "platform", "soda_user"];
+const COMMAND_LABELS: &[&str] = &["command"];
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            sessions_created: Counter::new(
+                "soda_sessions_created_total",
+                "Sessions created by the hub.",
+                SESSION_LABELS,
+            ),
+            sessions_deleted: Counter::new(
+                "soda_sessions_deleted_total",
+                "Sessions deleted by the clients.",
+                SESSION_LABELS,
+            ),
+            sessions_failed: Counter::new(
+                "soda_sessions_failed_total",
+                "New session requests rejected by the hub.",
+                SESSION_LABELS,
+            ),
+            sessions_reclaimed: Counter::new(
+                "soda_sessions_reclaimed_total",
+                "Idle sessions deleted on the hub by the proxy.",
+                SESSION_LABELS,
+            ),
+            request_duration: Histogram::new(
+                "soda_request_duration_seconds",
+                "Duration of the requests forwarded to the hub by WebDriver command.",
+                COMMAND_LABELS,
+            ),
+            hub_server_errors: Counter::new(
+                "soda_hub_server_errors_total",
+                "Responses of the hub with a 5xx status code.",
+                &["command", "status"],
+            ),
+            retries: Counter::new(
+                "soda_request_retries_total",
+                "Requests sent again to the hub after an error.",
+                COMMAND_LABELS,
+            ),
+            in_flight: AtomicI64::new(0),
+        }
+    }
+
+    pub fn session_changed(&self, status: SessionStatus, capabilities: &DesiredCapabilities) {
+        let counter = match status {
+            SessionStatus::Active => &self.sessions_created,
+            SessionStatus::Deleted => &self.sessions_deleted,
+            SessionStatus::Failed => &self.sessions_failed,
+            SessionStatus::Orphaned => &self.sessions_reclaimed,
+            _ => return,
+        };
+
+        counter.inc(&session_labels(capabilities));
+    }
+
+    pub fn request_done(&self, command: &str, duration: Duration, status: u16) {
+        self.request_duration
+            .observe(&[command], duration.as_secs_f64());
+
+        if status >= 500 {
+            self.hub_server_errors.inc(&[command, &status.to_string()]);
+        }
+    }
+
+    pub fn retried(&self, command: &str) {
+        self.retries.inc(&[command]);
+    }
+
+    pub fn in_flight(&self) -> InFlightGuard<'_> {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard {
+            gauge: &self.in_flight,
+        }
+    }
+
+    pub fn requests_in_flight(&self) -> i64 {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    pub fn render(&self, sessions: &SessionRegistry) -> String {
+        let mut out = String::new();
+
+        self.sessions_created.render(&mut out);
+        self.sessions_deleted.render(&mut out);
+        self.sessions_failed.render(&mut out);
+        self.sessions_reclaimed.render(&mut out);
+        self.request_duration.render(&mut out);
+        self.hub_server_errors.render(&mut out);
+        self.retries.render(&mut out);
+
+        header(
+            &mut out,
+            "soda_requests_in_flight",
+            "Requests currently forwarded to the hub.",
+            "gauge",
+        );
+        let _ = writeln!(
+            out,
+            "soda_requests_in_flight {}",
+            self.in_flight.load(Ordering::SeqCst)
+        );
+
+        let sessions = sessions.list();
+        let active_sessions = sessions
+            .iter()
+            .filter(|session| session.status == SessionStatus::Active)
+            .count();
+        header(
+            &mut out,
+            "soda_active_sessions",
+            "Sessions currently open on the hub.",
+            "gauge",
+        );
+        let _ = writeln!(out, "soda_active_sessions {}", active_sessions);
+
+        let mut queued_sessions: BTreeMap<&str, usize> = BTreeMap::new();
+        for session in &sessions {
+            if session.status == SessionStatus::Queued {
+                *queued_sessions.entry(&session.owner).or_insert(0) += 1;
+            }
+        }
+        header(
+            &mut out,
+            "soda_queued_sessions",
+            "New sessions waiting for a slot on the grid.",
+            "gauge",
+        );
+        for (owner, count) in queued_sessions {
+            let _ = writeln!(
+                out,
+                "soda_queued_sessions{} {}",
+                labels(&["soda_user"], &[owner.to_string()], None),
+                count
+            );
+        }
+
+        out
+    }
+}
+
+/// Label values for the session counters, missing capabilities are
+/// reported as empty strings and a missing user as GUEST.
+fn session_labels(capabilities: &DesiredCapabilities) -> [&str; 3] {
+    [
+        capabilities.browser_name.as_deref().unwrap_or(""),
+        capabilities.platform.as_deref().unwrap_or(""),
+        capabilities.soda_user.as_deref().unwrap_or("GUEST"),
+    ]
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn labels(names: &[&str], values: &[String], le: Option<&str>) -> String {
+    let mut pairs: Vec<String> = names
+        .iter()
+        .zip(values.iter())
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect();
+
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+
+    if pairs.is_empty() {
+        return String::new();
+    }
+
+    format!("{{{}}}", pairs.join(","))
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_exposes_the_session_counters_by_capabilities() {
+        let metrics = Metrics::new();
+        let capabilities = DesiredCapabilities {
+            browser_name: Some("chrome".to_string()),
+            platform: Some("LINUX".to_string()),
+            soda_user: Some("team \"a\"".to_string()),
+            ..DesiredCapabilities::default()
+        };
+
+        metrics.session_changed(SessionStatus::Active, &capabilities);
+        metrics.session_changed(SessionStatus::Active, &capabilities);
+        metrics.session_changed(SessionStatus::Failed, &DesiredCapabilities::default());
+
+        let out = metrics.render(&SessionRegistry::new());
+
+        assert!(out.contains(
+            "soda_sessions_created_total{browser_name=\"chrome\",platform=\"LINUX\",soda_user=\"team \\\"a\\\"\"} 2"
+        ));
+        assert!(out.contains(
+            "soda_sessions_failed_total{browser_name=\"\",platform=\"\",soda_user=\"GUEST\"} 1"
+        ));
+    }
+
+    #[test]
+    fn render_exposes_the_request_durations_and_the_hub_errors() {
+        let metrics = Metrics::new();
+
+        metrics.request_done("POST url", Duration::from_millis(200), 200);
+        metrics.request_done("POST url", Duration::from_secs(3), 502);
+
+        let out = metrics.render(&SessionRegistry::new());
+
+        assert!(out
+            .contains("soda_request_duration_seconds_bucket{command=\"POST url\",le=\"0.25\"} 1"));
+        assert!(out
+            .contains("soda_request_duration_seconds_bucket{command=\"POST url\",le=\"+Inf\"} 2"));
+        assert!(out.contains("soda_request_duration_seconds_count{command=\"POST url\"} 2"));
+        assert!(out.contains("soda_hub_server_errors_total{command=\"POST url\",status=\"502\"} 1"));
+    }
+
+    #[test]
+    fn in_flight_is_decreased_when_the_guard_is_dropped() {
+        let metrics = Metrics::new();
+
+        let guard = metrics.in_flight();
+        assert!(metrics
+            .render(&SessionRegistry::new())
+            .contains("soda_requests_in_flight 1"));
+
+        drop(guard);
+        assert!(metrics
+            .render(&SessionRegistry::new())
+            .contains("soda_requests_in_flight 0"));
+    }
+}
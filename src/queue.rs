@@ -0,0 +1,461 @@
+use crate::domain::SessionEvent;
+use crate::logging::{self, Event};
+use crate::registry::SessionRegistry;
+use crate::reverse_proxy::CapturedRequest;
+use chrono::{DateTime, Utc};
+use log::Level;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use uuid::Uuid;
+
+#[derive(Serialize)]
+struct QueuedEvent {
+    #[serde(skip)]
+    event: SessionEvent,
+    owner: String,
+    position: usize,
+    depth: usize,
+}
+
+impl fmt::Display for QueuedEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[{}] [{}] (position: {}, queue depth: {})",
+            self.event, self.owner, self.position, self.depth
+        )
+    }
+}
+
+impl Event for QueuedEvent {
+    fn name(&self) -> String {
+        self.event.to_string()
+    }
+}
+
+struct Waiter {
+    request_id: Uuid,
+    since: DateTime<Utc>,
+    ready: oneshot::Sender<()>,
+}
+
+/// The requests waiting for a slot, by owner (`soda:user`).
+#[derive(Default)]
+struct Waiting {
+    by_owner: BTreeMap<String, VecDeque<Waiter>>,
+    /// Owner of the last request which got a slot, the next owner is served next.
+    last_served: Option<String>,
+}
+
+impl Waiting {
+    fn depth(&self) -> usize {
+        self.by_owner.values().map(VecDeque::len).sum()
+    }
+
+    /// Owners with a waiting request, starting with the next one to be served.
+    fn owners_in_turn(&self) -> Vec<&String> {
+        let (after, before): (Vec<&String>, Vec<&String>) =
+            self.by_owner
+                .keys()
+                .partition(|owner| match &self.last_served {
+                    Some(last_served) => *owner > last_served,
+                    None => true,
+                });
+        after.into_iter().chain(before).collect()
+    }
+
+    /// The order in which the waiting requests will get a slot : one request
+    /// of each owner in turn, the oldest request of an owner first.
+    fn order(&self) -> Vec<(&String, &Waiter)> {
+        let owners = self.owners_in_turn();
+        let mut order = Vec::with_capacity(self.depth());
+
+        for round in 0.. {
+            let before = order.len();
+            for owner in &owners {
+                if let Some(waiter) = self.by_owner[*owner].get(round) {
+                    order.push((*owner, waiter));
+                }
+            }
+            if order.len() == before {
+                break;
+            }
+        }
+
+        order
+    }
+
+    fn pop_next(&mut self) -> Option<Waiter> {
+        let owner = self
+            .owners_in_turn()
+            .first()
+            .map(|owner| owner.to_string())?;
+        let waiters = self.by_owner.get_mut(&owner)?;
+        let waiter = waiters.pop_front();
+
+        if waiters.is_empty() {
+            self.by_owner.remove(&owner);
+        }
+        self.last_served = Some(owner);
+        waiter
+    }
+
+    fn remove(&mut self, request_id: Uuid) -> bool {
+        let mut removed = false;
+        for waiters in self.by_owner.values_mut() {
+            let len = waiters.len();
+            waiters.retain(|waiter| waiter.request_id != request_id);
+            removed |= waiters.len() != len;
+        }
+        self.by_owner.retain(|_, waiters| !waiters.is_empty());
+        removed
+    }
+}
+
+/// A waiting request as shown by the admin API.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueuedRequest {
+    pub request_id: Uuid,
+    pub owner: String,
+    /// 1 for the next request to get a slot
+    pub position: usize,
+    pub waiting_secs: i64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueueSnapshot {
+    pub capacity: Option<usize>,
+    pub depth: usize,
+    pub depth_by_owner: BTreeMap<String, usize>,
+    pub requests: Vec<QueuedRequest>,
+}
+
+/// Hold the new session requests while the grid is full, instead of letting
+/// them wait in the hub. The free slots are given to each owner in turn so
+/// that a large build can't starve the other users.
+pub struct SessionQueue {
+    limits: RwLock<Limits>,
+    waiting: Mutex<Waiting>,
+}
+
+#[derive(Clone, Copy)]
+struct Limits {
+    /// Maximum number of sessions being created or open on the grid,
+    /// the requests are never held without it.
+    capacity: Option<usize>,
+    max_wait: Duration,
+}
+
+impl SessionQueue {
+    pub fn new(capacity: Option<usize>, max_wait: Duration) -> SessionQueue {
+        SessionQueue {
+            limits: RwLock::new(Limits { capacity, max_wait }),
+            waiting: Mutex::new(Waiting::default()),
+        }
+    }
+
+    /// Change the capacity and the maximum wait, e.g. when the configuration
+    /// is reloaded. The waiting requests stay in the queue.
+    pub fn set_limits(&self, capacity: Option<usize>, max_wait: Duration) {
+        *self.limits.write().unwrap() = Limits { capacity, max_wait };
+    }
+
+    /// Wait until the new session can be sent to the hub. The request is
+    /// removed from the queue when it waits longer than the maximum wait, or
+    /// when the client goes away.
+    pub async fn wait_for_a_slot<'m, 'b>(
+        &self,
+        request: &CapturedRequest<'m, 'b>,
+        owner: &str,
+        sessions: &SessionRegistry,
+    ) -> Result<(), String> {
+        let Limits { capacity, max_wait } = *self.limits.read().unwrap();
+        let capacity = match capacity {
+            Some(capacity) => capacity,
+            None => {
+                sessions.start_creating(request.id);
+                return Ok(());
+            }
+        };
+
+        let (ready, mut slot) = oneshot::channel();
+        {
+            let mut waiting = self.waiting.lock().unwrap();
+            waiting
+                .by_owner
+                .entry(owner.to_string())
+                .or_default()
+                .push_back(Waiter {
+                    request_id: request.id,
+                    since: Utc::now(),
+                    ready,
+                });
+        }
+        let _leave_on_drop = LeaveOnDrop {
+            queue: self,
+            sessions,
+            request_id: request.id,
+        };
+
+        self.dispatch(sessions);
+
+        if let Some(position) = self.position_of(request.id) {
+            let event = QueuedEvent {
+                event: SessionEvent::Queued,
+                owner: owner.to_string(),
+                position,
+                depth: self.waiting.lock().unwrap().depth(),
+            };
+            logging::event(Level::Info, request, &event, None);
+        }
+
+        match tokio::time::timeout(max_wait, &mut slot).await {
+            Ok(Ok(())) => return Ok(()),
+            Ok(Err(_)) => return Err("The proxy is shutting down".to_string()),
+            Err(_) => {}
+        }
+
+        // The slot may have been given while the wait was ending
+        let removed = self.waiting.lock().unwrap().remove(request.id);
+        match removed {
+            true => Err(format!(
+                "The session waited {}s in the queue of the proxy without any free slot on the grid (capacity: {} sessions)",
+                max_wait.as_secs(),
+                capacity
+            )),
+            false => Ok(()),
+        }
+    }
+
+    /// Give the free slots of the grid to the waiting requests, to be called
+    /// whenever a session may have ended.
+    pub fn dispatch(&self, sessions: &SessionRegistry) {
+        let capacity = match self.limits.read().unwrap().capacity {
+            Some(capacity) => capacity,
+            None => return,
+        };
+
+        let mut waiting = self.waiting.lock().unwrap();
+        while sessions.opened_sessions() < capacity {
+            match waiting.pop_next() {
+                Some(waiter) => {
+                    sessions.start_creating(waiter.request_id);
+                    // The client went away, the session never started
+                    if waiter.ready.send(()).is_err() {
+                        sessions.fail(waiter.request_id);
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Refuse every waiting request, e.g. when the proxy is shutting down.
+    pub fn close(&self) {
+        let mut waiting = self.waiting.lock().unwrap();
+        waiting.by_owner.clear();
+    }
+
+    fn position_of(&self, request_id: Uuid) -> Option<usize> {
+        self.waiting
+            .lock()
+            .unwrap()
+            .order()
+            .iter()
+            .position(|(_, waiter)| waiter.request_id == request_id)
+            .map(|position| position + 1)
+    }
+
+    pub fn snapshot(&self) -> QueueSnapshot {
+        let waiting = self.waiting.lock().unwrap();
+        let now = Utc::now();
+
+        QueueSnapshot {
+            capacity: self.limits.read().unwrap().capacity,
+            depth: waiting.depth(),
+            depth_by_owner: waiting
+                .by_owner
+                .iter()
+                .map(|(owner, waiters)| (owner.to_owned(), waiters.len()))
+                .collect(),
+            requests: waiting
+                .order()
+                .iter()
+                .enumerate()
+                .map(|(position, (owner, waiter))| QueuedRequest {
+                    request_id: waiter.request_id,
+                    owner: owner.to_string(),
+                    position: position + 1,
+                    waiting_secs: (now - waiter.since).num_seconds(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Remove the request from the queue when its client goes away, and fail its
+/// session so that it no longer counts in the quota of its owner.
+struct LeaveOnDrop<'a> {
+    queue: &'a SessionQueue,
+    sessions: &'a SessionRegistry,
+    request_id: Uuid,
+}
+
+impl Drop for LeaveOnDrop<'_> {
+    fn drop(&mut self) {
+        if self.queue.waiting.lock().unwrap().remove(self.request_id) {
+            self.sessions.fail(self.request_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{DesiredCapabilities, SessionStatus};
+    use bytes::Bytes;
+    use hyper::Method;
+    use std::sync::Arc;
+    use url::Url;
+
+    fn queue_request(sessions: &SessionRegistry, owner: &str) -> Uuid {
+        let request_id = Uuid::new_v4();
+        let capabilities = DesiredCapabilities {
+            soda_user: Some(owner.to_string()),
+            ..DesiredCapabilities::default()
+        };
+        sessions.queue(request_id, capabilities);
+        request_id
+    }
+
+    async fn wait(
+        queue: Arc<SessionQueue>,
+        sessions: SessionRegistry,
+        request_id: Uuid,
+        owner: &str,
+    ) -> Result<(), String> {
+        let body = Bytes::new();
+        let request = CapturedRequest {
+            id: request_id,
+            url: Url::parse("http://localhost:4444/wd/hub/session").unwrap(),
+            method: &Method::POST,
+            path: "/wd/hub/session".to_string(),
+            body: &body,
+            user: None,
+        };
+        queue.wait_for_a_slot(&request, owner, &sessions).await
+    }
+
+    #[test]
+    fn order_serves_each_owner_in_turn() {
+        let mut waiting = Waiting::default();
+        for (owner, count) in &[("team-a", 3), ("team-b", 1), ("team-c", 2)] {
+            for _ in 0..*count {
+                let (ready, _) = oneshot::channel();
+                waiting
+                    .by_owner
+                    .entry(owner.to_string())
+                    .or_default()
+                    .push_back(Waiter {
+                        request_id: Uuid::new_v4(),
+                        since: Utc::now(),
+                        ready,
+                    });
+            }
+        }
+        waiting.last_served = Some("team-a".to_string());
+
+        let owners: Vec<&str> = waiting
+            .order()
+            .iter()
+            .map(|(owner, _)| owner.as_str())
+            .collect();
+        assert_eq!(
+            owners,
+            vec!["team-b", "team-c", "team-a", "team-c", "team-a", "team-a"]
+        );
+
+        assert!(waiting.pop_next().is_some());
+        assert_eq!(waiting.last_served, Some("team-b".to_string()));
+        assert!(!waiting.by_owner.contains_key("team-b"));
+    }
+
+    #[tokio::test]
+    async fn wait_for_a_slot_holds_the_request_until_a_session_ends() {
+        let sessions = SessionRegistry::new();
+        let queue = Arc::new(SessionQueue::new(Some(1), Duration::from_secs(5)));
+
+        let first = queue_request(&sessions, "team-a");
+        assert!(wait(queue.clone(), sessions.clone(), first, "team-a")
+            .await
+            .is_ok());
+        assert_eq!(
+            sessions.get(&first.to_string()).unwrap().status,
+            SessionStatus::Creating
+        );
+
+        let second = queue_request(&sessions, "team-b");
+        let waiting = tokio::spawn(wait(queue.clone(), sessions.clone(), second, "team-b"));
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+        assert_eq!(queue.snapshot().depth, 1);
+
+        sessions.fail(first);
+        queue.dispatch(&sessions);
+
+        assert!(waiting.await.unwrap().is_ok());
+        assert_eq!(queue.snapshot().depth, 0);
+    }
+
+    #[tokio::test]
+    async fn wait_for_a_slot_gives_up_after_the_maximum_wait() {
+        let sessions = SessionRegistry::new();
+        let queue = Arc::new(SessionQueue::new(Some(0), Duration::from_millis(50)));
+        let request_id = queue_request(&sessions, "team-a");
+
+        let result = wait(queue.clone(), sessions.clone(), request_id, "team-a").await;
+
+        assert!(result.is_err());
+        assert_eq!(queue.snapshot().depth, 0);
+    }
+
+    #[tokio::test]
+    async fn wait_for_a_slot_fails_the_session_when_the_client_goes_away() {
+        let sessions = SessionRegistry::new();
+        let queue = Arc::new(SessionQueue::new(Some(0), Duration::from_secs(5)));
+        let request_id = queue_request(&sessions, "team-a");
+
+        let waiting = wait(queue.clone(), sessions.clone(), request_id, "team-a");
+        assert!(tokio::time::timeout(Duration::from_millis(50), waiting)
+            .await
+            .is_err());
+
+        assert_eq!(queue.snapshot().depth, 0);
+        assert_eq!(
+            sessions.get(&request_id.to_string()).unwrap().status,
+            SessionStatus::Failed
+        );
+        assert_eq!(sessions.live_sessions_by_owner().get("team-a"), None);
+    }
+
+    #[tokio::test]
+    async fn close_refuses_the_waiting_requests() {
+        let sessions = SessionRegistry::new();
+        let queue = Arc::new(SessionQueue::new(Some(0), Duration::from_secs(5)));
+        let request_id = queue_request(&sessions, "team-a");
+
+        let waiting = tokio::spawn(wait(queue.clone(), sessions.clone(), request_id, "team-a"));
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+        queue.close();
+
+        assert_eq!(
+            waiting.await.unwrap(),
+            Err("The proxy is shutting down".to_string())
+        );
+        assert_eq!(queue.snapshot().depth, 0);
+    }
+}
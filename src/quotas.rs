@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Maximum number of concurrent sessions of a user or a team.
+/// Format : `NAME=MAX_SESSIONS`, e.g. `my-team=10` or `GUEST=2`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Quota {
+    pub name: String,
+    pub max_sessions: usize,
+}
+
+impl FromStr for Quota {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((name, max_sessions)) if !name.trim().is_empty() => {
+                let max_sessions = max_sessions.trim().parse::<usize>().map_err(|_| {
+                    format!("Quota {} : the maximum must be a number of sessions", s)
+                })?;
+                Ok(Quota {
+                    name: name.trim().to_string(),
+                    max_sessions,
+                })
+            }
+            _ => Err(format!("Quota {} : format must be NAME=MAX_SESSIONS", s)),
+        }
+    }
+}
+
+/// Users (`soda:user` values) sharing a quota.
+/// Format : `TEAM=USER[,USER...]`, e.g. `payment=payment-api,payment-front`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Team {
+    pub name: String,
+    pub members: Vec<String>,
+}
+
+impl FromStr for Team {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((name, members)) if !name.trim().is_empty() && !members.trim().is_empty() => {
+                Ok(Team {
+                    name: name.trim().to_string(),
+                    members: members
+                        .split(',')
+                        .map(|member| member.trim().to_string())
+                        .collect(),
+                })
+            }
+            _ => Err(format!("Team {} : format must be TEAM=USER[,USER...]", s)),
+        }
+    }
+}
+
+/// The quotas of the users and of the teams. A user without any quota,
+/// and outside of a team with a quota, is not limited.
+#[derive(Default)]
+pub struct Quotas {
+    max_sessions: HashMap<String, usize>,
+    teams: Vec<Team>,
+}
+
+impl Quotas {
+    pub fn new(quotas: Vec<Quota>, teams: Vec<Team>) -> Quotas {
+        Quotas {
+            max_sessions: quotas
+                .into_iter()
+                .map(|quota| (quota.name, quota.max_sessions))
+                .collect(),
+            teams,
+        }
+    }
+
+    /// Check that a new session of `owner` is allowed. `live_sessions` is the
+    /// number of live sessions by owner, including the new one.
+    pub fn check(&self, owner: &str, live_sessions: &HashMap<String, usize>) -> Result<(), String> {
+        let count_of = |user: &str| live_sessions.get(user).copied().unwrap_or(0);
+
+        if let Some(max_sessions) = self.max_sessions.get(owner) {
+            if count_of(owner) > *max_sessions {
+                return Err(format!(
+                    "The user {} has reached its quota of {} concurrent sessions",
+                    owner, max_sessions
+                ));
+            }
+        }
+
+        for team in self.teams_of(owner) {
+            if let Some(max_sessions) = self.max_sessions.get(&team.name) {
+                let count: usize = team.members.iter().map(|member| count_of(member)).sum();
+                if count > *max_sessions {
+                    return Err(format!(
+                        "The team {} of the user {} has reached its quota of {} concurrent sessions",
+                        team.name, owner, max_sessions
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn teams_of<'a>(&'a self, user: &'a str) -> impl Iterator<Item = &'a Team> {
+        self.teams
+            .iter()
+            .filter(move |team| team.members.iter().any(|member| member == user))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn live_sessions(counts: &[(&str, usize)]) -> HashMap<String, usize> {
+        counts
+            .iter()
+            .map(|(user, count)| (user.to_string(), *count))
+            .collect()
+    }
+
+    #[test]
+    fn quota_and_team_are_parsed_from_the_cli_format() {
+        assert_eq!(
+            "GUEST=2".parse::<Quota>(),
+            Ok(Quota {
+                name: "GUEST".to_string(),
+                max_sessions: 2
+            })
+        );
+        assert!("GUEST".parse::<Quota>().is_err());
+        assert!("GUEST=many".parse::<Quota>().is_err());
+        assert_eq!(
+            "payment=payment-api,payment-front".parse::<Team>(),
+            Ok(Team {
+                name: "payment".to_string(),
+                members: vec!["payment-api".to_string(), "payment-front".to_string()]
+            })
+        );
+        assert!("payment=".parse::<Team>().is_err());
+    }
+
+    #[test]
+    fn check_rejects_a_user_over_its_quota() {
+        let quotas = Quotas::new(vec!["GUEST=2".parse().unwrap()], vec![]);
+
+        assert!(quotas
+            .check("GUEST", &live_sessions(&[("GUEST", 2)]))
+            .is_ok());
+        assert!(quotas
+            .check("GUEST", &live_sessions(&[("GUEST", 3)]))
+            .is_err());
+        assert!(quotas
+            .check("my-team", &live_sessions(&[("my-team", 50)]))
+            .is_ok());
+    }
+
+    #[test]
+    fn check_rejects_a_member_of_a_team_over_its_quota() {
+        let quotas = Quotas::new(
+            vec!["payment=3".parse().unwrap()],
+            vec!["payment=payment-api,payment-front".parse().unwrap()],
+        );
+
+        let under = live_sessions(&[("payment-api", 2), ("payment-front", 1), ("GUEST", 5)]);
+        let over = live_sessions(&[("payment-api", 2), ("payment-front", 2)]);
+
+        assert!(quotas.check("payment-front", &under).is_ok());
+        assert_eq!(
+            quotas.check("payment-front", &over),
+            Err("The team payment of the user payment-front has reached its quota of 3 concurrent sessions".to_string())
+        );
+    }
+}
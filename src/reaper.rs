@@ -0,0 +1,205 @@
+use crate::domain::{Session, SessionEvent};
+use crate::logging::{self, Event};
+use crate::AppState;
+use chrono::Utc;
+use log::Level;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The reaper never waits more than this between two checks.
+const MAX_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Timeout of the delete requests sent to the hub.
+const DELETE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Serialize)]
+struct OrphanedEvent {
+    #[serde(skip)]
+    event: SessionEvent,
+    #[serde(skip)]
+    session_id: String,
+    owner: String,
+    idle_secs: i64,
+}
+
+impl fmt::Display for OrphanedEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[{}] [{}] [{}] (idle for {}s)",
+            self.event, self.session_id, self.owner, self.idle_secs
+        )
+    }
+}
+
+impl Event for OrphanedEvent {
+    fn name(&self) -> String {
+        self.event.to_string()
+    }
+
+    fn session_id(&self) -> Option<&str> {
+        Some(&self.session_id)
+    }
+}
+
+/// Periodically delete on the hub the sessions without any command since
+/// `idle_timeout`, e.g. when a CI job crashed without quitting the browser.
+pub async fn run(state: Arc<AppState>, idle_timeout: Duration) {
+    let check_interval = std::cmp::min(idle_timeout, MAX_CHECK_INTERVAL);
+
+    info!(
+        "Sessions idle for more than {}s will be deleted",
+        idle_timeout.as_secs()
+    );
+
+    loop {
+        tokio::time::delay_for(check_interval).await;
+        reap(&state, idle_timeout).await;
+    }
+}
+
+async fn reap(state: &AppState, idle_timeout: Duration) {
+    let threshold = Utc::now()
+        - chrono::Duration::from_std(idle_timeout).unwrap_or_else(|_| chrono::Duration::zero());
+
+    let mut reclaimed = 0;
+    for session in state.sessions.idle_since(threshold) {
+        // The browser is still busy until the hub deleted the session,
+        // its slot is kept and the delete is tried again on the next check
+        if !delete_on_hub(state, &session).await {
+            continue;
+        }
+
+        // The client may have used the session while we were deleting it
+        if let Some(orphaned) = state.sessions.orphan(&session.id) {
+            state
+                .metrics
+                .session_changed(orphaned.status, &orphaned.capabilities);
+
+            let event = OrphanedEvent {
+                event: SessionEvent::Orphaned,
+                session_id: orphaned.id,
+                owner: orphaned.owner,
+                idle_secs: (Utc::now() - session.last_activity).num_seconds(),
+            };
+            logging::proxy_event(Level::Warn, &event);
+            reclaimed += 1;
+        }
+    }
+
+    if reclaimed > 0 {
+        state.queue.dispatch(&state.sessions);
+    }
+}
+
+/// Delete the session on its hub, under the base path of the hubs, whether
+/// the hub deleted it.
+pub async fn delete_on_hub(state: &AppState, session: &Session) -> bool {
+    let settings = state.settings();
+    let hub = session.hub.as_ref().unwrap_or(&settings.forward_uri);
+    let url = format!(
+        "http://{}{}/session/{}",
+        hub, settings.base_path, session.id
+    );
+
+    match state
+        .client
+        .delete(&url)
+        .timeout(DELETE_TIMEOUT)
+        .send()
+        .await
+    {
+        Ok(response) if response.status().is_success() => true,
+        Ok(response) => {
+            error!(
+                "The hub refused to delete the session {} : {}",
+                session.id,
+                response.status()
+            );
+            false
+        }
+        Err(err) => {
+            error!("Fail to delete the session {} : {}", session.id, err);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+    use crate::domain::{DesiredCapabilities, SessionStatus};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server, StatusCode};
+    use std::convert::Infallible;
+    use uuid::Uuid;
+
+    /// A hub answering the deletes under `base_path` with `status`, the
+    /// other requests with a 404.
+    fn hub_answering(status: StatusCode, base_path: &'static str) -> String {
+        let make_svc = make_service_fn(move |_| async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| async move {
+                let mut response = Response::new(Body::from("{\"value\":null}"));
+                *response.status_mut() = match req
+                    .uri()
+                    .path()
+                    .starts_with(&format!("{}/session/", base_path))
+                {
+                    true => status,
+                    false => StatusCode::NOT_FOUND,
+                };
+                Ok::<_, Infallible>(response)
+            }))
+        });
+
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+        let address = server.local_addr();
+        tokio::spawn(server);
+
+        address.to_string()
+    }
+
+    #[tokio::test]
+    async fn reap_only_reclaims_the_sessions_deleted_by_the_hub() {
+        let state = AppState::new(hub_answering(StatusCode::OK, "/wd/hub"), 60);
+        state
+            .sessions
+            .activate(Uuid::new_v4(), "deleted", DesiredCapabilities::default());
+        state
+            .sessions
+            .activate(Uuid::new_v4(), "refused", DesiredCapabilities::default());
+        state.sessions.set_hub(
+            "refused",
+            &hub_answering(StatusCode::INTERNAL_SERVER_ERROR, "/wd/hub"),
+        );
+
+        reap(&state, Duration::from_secs(0)).await;
+
+        assert_eq!(
+            state.sessions.get("deleted").unwrap().status,
+            SessionStatus::Orphaned
+        );
+        assert_eq!(
+            state.sessions.get("refused").unwrap().status,
+            SessionStatus::Active
+        );
+    }
+
+    #[tokio::test]
+    async fn delete_on_hub_deletes_the_session_under_the_base_path() {
+        let mut settings = Settings::new(hub_answering(StatusCode::OK, ""), 60);
+        settings.base_path = "".to_string();
+        let state = AppState::with_settings(settings);
+        let session =
+            state
+                .sessions
+                .activate(Uuid::new_v4(), "123", DesiredCapabilities::default());
+
+        assert!(delete_on_hub(&state, &session).await);
+
+        state.set_settings(Settings::new(state.settings().forward_uri.to_owned(), 60));
+        assert!(!delete_on_hub(&state, &session).await);
+    }
+}
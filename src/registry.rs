@@ -0,0 +1,395 @@
+use crate::domain::{DesiredCapabilities, Session, SessionStatus};
+use chrono::{DateTime, Utc};
+use reqwest::Client;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+/// How many finished sessions we keep to be able to look at the recent ones.
+const MAX_FINISHED_SESSIONS: usize = 1000;
+
+/// Keep track of every test session going through the proxy.
+/// Sessions waiting for the hub are keyed by their request id, then
+/// by the session id given by the hub once created.
+#[derive(Clone, Default)]
+pub struct SessionRegistry {
+    sessions: Arc<Mutex<HashMap<String, Session>>>,
+    /// Ids of the finished sessions, the oldest first
+    finished: Arc<Mutex<VecDeque<String>>>,
+}
+
+impl SessionRegistry {
+    pub fn new() -> SessionRegistry {
+        SessionRegistry::default()
+    }
+
+    /// A new session request has been received and is waiting for the hub.
+    pub fn queue(&self, request_id: Uuid, capabilities: DesiredCapabilities) {
+        let id = request_id.to_string();
+        let session = Session::new(id.to_owned(), request_id, capabilities);
+
+        self.sessions.lock().unwrap().insert(id, session);
+    }
+
+    /// The hub created the session, it's now known by its session id.
+    pub fn activate(
+        &self,
+        request_id: Uuid,
+        session_id: &str,
+        granted: DesiredCapabilities,
+    ) -> Session {
+        let mut sessions = self.sessions.lock().unwrap();
+        let mut session = sessions
+            .remove(&request_id.to_string())
+            .unwrap_or_else(|| Session::new(session_id.to_string(), request_id, granted.clone()));
+
+        session.id = session_id.to_string();
+        session.capabilities = granted.or(session.capabilities);
+        session.status = SessionStatus::Active;
+        session.last_activity = Utc::now();
+
+        sessions.insert(session_id.to_string(), session.clone());
+        session
+    }
+
+    /// The new session request got a slot on the grid and is sent to the hub.
+    pub fn start_creating(&self, request_id: Uuid) {
+        if let Some(session) = self
+            .sessions
+            .lock()
+            .unwrap()
+            .get_mut(&request_id.to_string())
+        {
+            if session.status == SessionStatus::Queued {
+                session.status = SessionStatus::Creating;
+            }
+        }
+    }
+
+    /// The hub refused to create the session.
+    pub fn fail(&self, request_id: Uuid) -> Option<Session> {
+        self.finish(&request_id.to_string(), SessionStatus::Failed)
+    }
+
+    /// A command has been sent to the session. The sessions are only
+    /// registered when created or handed off : a command of an unknown
+    /// session (e.g. a typo or a session already forgotten) is ignored.
+    pub fn touch(&self, session_id: &str, request_id: Uuid, url: Option<String>) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = match sessions.get_mut(session_id) {
+            Some(session) => session,
+            None => {
+                debug!(
+                    "The session {} of the request {} is unknown",
+                    session_id, request_id
+                );
+                return;
+            }
+        };
+
+        session.last_activity = Utc::now();
+        session.command_count += 1;
+        if url.is_some() {
+            session.last_url = url;
+        }
+    }
+
+    /// The client asked the hub to delete the session.
+    pub fn delete(&self, session_id: &str) -> Option<Session> {
+        self.finish(session_id, SessionStatus::Deleted)
+    }
+
+    /// The proxy deleted the session on the hub because it was idle.
+    pub fn orphan(&self, session_id: &str) -> Option<Session> {
+        self.finish(session_id, SessionStatus::Orphaned)
+    }
+
+    /// Active sessions without any command since the given instant.
+    pub fn idle_since(&self, instant: DateTime<Utc>) -> Vec<Session> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|session| session.status == SessionStatus::Active)
+            .filter(|session| session.last_activity < instant)
+            .cloned()
+            .collect()
+    }
+
+    /// Number of active sessions by hub address.
+    pub fn active_sessions_by_hub(&self) -> HashMap<String, usize> {
+        let mut active_sessions = HashMap::new();
+
+        for session in self.sessions.lock().unwrap().values() {
+            if !session.status.is_finished() {
+                if let Some(hub) = &session.hub {
+                    *active_sessions.entry(hub.to_owned()).or_insert(0) += 1;
+                }
+            }
+        }
+
+        active_sessions
+    }
+
+    /// Number of sessions being created or open on the grid,
+    /// the ones waiting for a slot are not counted.
+    pub fn opened_sessions(&self) -> usize {
+        self.sessions
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|session| {
+                session.status != SessionStatus::Queued && !session.status.is_finished()
+            })
+            .count()
+    }
+
+    /// Number of live (queued or not yet finished) sessions by owner.
+    pub fn live_sessions_by_owner(&self) -> HashMap<String, usize> {
+        let mut live_sessions = HashMap::new();
+
+        for session in self.sessions.lock().unwrap().values() {
+            if !session.status.is_finished() {
+                *live_sessions.entry(session.owner.to_owned()).or_insert(0) += 1;
+            }
+        }
+
+        live_sessions
+    }
+
+    pub fn set_hub(&self, id: &str, hub: &str) {
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(id) {
+            session.hub = Some(hub.to_string());
+        }
+    }
+
+    pub fn set_node(&self, session_id: &str, node: String) {
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(session_id) {
+            session.node = Some(node);
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<Session> {
+        self.sessions.lock().unwrap().get(id).cloned()
+    }
+
+    pub fn list(&self) -> Vec<Session> {
+        self.sessions.lock().unwrap().values().cloned().collect()
+    }
+
+    /// The sessions not finished yet, e.g. to report them at shutdown.
+    pub fn open_sessions(&self) -> Vec<Session> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|session| !session.status.is_finished())
+            .cloned()
+            .collect()
+    }
+
+    /// Register a session opened through another proxy, which handed it off.
+    pub fn adopt(&self, session: Session) {
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(session.id.to_owned(), session);
+    }
+
+    /// End the session, the finished session is returned unless it was
+    /// already finished or unknown.
+    fn finish(&self, id: &str, status: SessionStatus) -> Option<Session> {
+        let mut sessions = self.sessions.lock().unwrap();
+
+        let finished_session = match sessions.get_mut(id) {
+            Some(session) if !session.status.is_finished() => {
+                session.status = status;
+                session.last_activity = Utc::now();
+                session.clone()
+            }
+            _ => return None,
+        };
+
+        // Forget the oldest finished sessions
+        let mut finished = self.finished.lock().unwrap();
+        finished.push_back(id.to_string());
+        while finished.len() > MAX_FINISHED_SESSIONS {
+            if let Some(oldest) = finished.pop_front() {
+                sessions.remove(&oldest);
+            }
+        }
+
+        Some(finished_session)
+    }
+}
+
+/// Ask the hub (Selenium Grid 3) which node runs the session.
+/// The lookup is best effort : a hub without this API leaves the node empty.
+pub async fn lookup_node(
+    client: Client,
+    hub: String,
+    session_id: String,
+    sessions: SessionRegistry,
+) {
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct TestSession {
+        proxy_id: Option<String>,
+    }
+
+    let url = format!("http://{}/grid/api/testsession?session={}", hub, session_id);
+    let test_session = match client.get(&url).send().await {
+        Ok(response) => match response.bytes().await {
+            Ok(body) => serde_json::from_slice::<TestSession>(&body).ok(),
+            Err(_) => None,
+        },
+        Err(err) => {
+            debug!(
+                "Fail to retrieve the node of the session {} : {}",
+                session_id, err
+            );
+            None
+        }
+    };
+
+    if let Some(node) = test_session.and_then(|test_session| test_session.proxy_id) {
+        sessions.set_node(&session_id, node);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capabilities_of(user: &str) -> DesiredCapabilities {
+        DesiredCapabilities {
+            browser_name: Some("chrome".to_string()),
+            soda_user: Some(user.to_string()),
+            ..DesiredCapabilities::default()
+        }
+    }
+
+    #[test]
+    fn activate_moves_the_queued_session_to_its_session_id() {
+        let sessions = SessionRegistry::new();
+        let request_id = Uuid::new_v4();
+
+        sessions.queue(request_id, capabilities_of("team-a"));
+        assert_eq!(
+            sessions.get(&request_id.to_string()).unwrap().status,
+            SessionStatus::Queued
+        );
+
+        sessions.activate(request_id, "123", DesiredCapabilities::default());
+
+        let session = sessions.get("123").unwrap();
+        assert!(sessions.get(&request_id.to_string()).is_none());
+        assert_eq!(session.status, SessionStatus::Active);
+        assert_eq!(session.owner, "team-a");
+        assert_eq!(
+            session.capabilities.browser_name,
+            Some("chrome".to_string())
+        );
+    }
+
+    #[test]
+    fn live_sessions_by_owner_ignores_the_finished_sessions() {
+        let sessions = SessionRegistry::new();
+        for session_id in &["1", "2", "3"] {
+            let request_id = Uuid::new_v4();
+            sessions.queue(request_id, capabilities_of("team-a"));
+            sessions.activate(request_id, session_id, DesiredCapabilities::default());
+        }
+        sessions.queue(Uuid::new_v4(), capabilities_of("team-b"));
+        sessions.delete("3");
+
+        let live_sessions = sessions.live_sessions_by_owner();
+
+        assert_eq!(live_sessions.get("team-a"), Some(&2));
+        assert_eq!(live_sessions.get("team-b"), Some(&1));
+    }
+
+    #[test]
+    fn touch_counts_the_commands_and_keeps_the_last_url() {
+        let sessions = SessionRegistry::new();
+        let request_id = Uuid::new_v4();
+        sessions.queue(request_id, capabilities_of("team-a"));
+        sessions.activate(request_id, "123", DesiredCapabilities::default());
+
+        sessions.touch(
+            "123",
+            Uuid::new_v4(),
+            Some("https://duckduckgo.com/".to_string()),
+        );
+        sessions.touch("123", Uuid::new_v4(), None);
+
+        let session = sessions.get("123").unwrap();
+        assert_eq!(session.command_count, 2);
+        assert_eq!(
+            session.last_url,
+            Some("https://duckduckgo.com/".to_string())
+        );
+    }
+
+    #[test]
+    fn idle_since_only_returns_the_active_sessions_without_recent_commands() {
+        let sessions = SessionRegistry::new();
+        for session_id in &["idle", "deleted", "busy"] {
+            sessions.activate(Uuid::new_v4(), session_id, DesiredCapabilities::default());
+        }
+        sessions.delete("deleted");
+        let threshold = Utc::now();
+        sessions.touch("busy", Uuid::new_v4(), None);
+        sessions.touch("unknown", Uuid::new_v4(), None);
+
+        let idle: Vec<String> = sessions
+            .idle_since(threshold)
+            .into_iter()
+            .map(|session| session.id)
+            .collect();
+
+        assert_eq!(idle, vec!["idle".to_string()]);
+        assert!(sessions.get("unknown").is_none());
+        assert!(sessions.orphan("idle").is_some());
+        assert!(sessions.orphan("idle").is_none());
+        assert_eq!(
+            sessions.get("idle").unwrap().status,
+            SessionStatus::Orphaned
+        );
+    }
+
+    #[test]
+    fn delete_and_fail_finish_the_sessions() {
+        let sessions = SessionRegistry::new();
+        let created = Uuid::new_v4();
+        let rejected = Uuid::new_v4();
+        sessions.queue(created, capabilities_of("team-a"));
+        sessions.queue(rejected, capabilities_of("team-b"));
+        sessions.activate(created, "123", DesiredCapabilities::default());
+
+        sessions.delete("123");
+        sessions.fail(rejected);
+
+        assert_eq!(sessions.get("123").unwrap().status, SessionStatus::Deleted);
+        assert_eq!(
+            sessions.get(&rejected.to_string()).unwrap().status,
+            SessionStatus::Failed
+        );
+    }
+
+    #[test]
+    fn finish_forgets_the_oldest_finished_sessions() {
+        let sessions = SessionRegistry::new();
+        for i in 0..=MAX_FINISHED_SESSIONS {
+            let session_id = i.to_string();
+            sessions.activate(Uuid::new_v4(), &session_id, DesiredCapabilities::default());
+            sessions.delete(&session_id);
+        }
+        sessions.activate(Uuid::new_v4(), "open", DesiredCapabilities::default());
+
+        assert!(sessions.get("0").is_none());
+        assert!(sessions.get("1").is_some());
+        assert!(sessions.get("open").is_some());
+        assert_eq!(sessions.list().len(), MAX_FINISHED_SESSIONS + 1);
+    }
+}
@@ -0,0 +1,146 @@
+use crate::commands::CommandPattern;
+use crate::logging::Event;
+use hyper::{Method, StatusCode};
+use retry::delay::{jitter, Exponential};
+use std::fmt;
+use std::time::Duration;
+
+/// The commands which can be sent twice without doing an action twice :
+/// the reads, the lookups of elements and the changes of context.
+/// A click, a script or a keystroke is never retried.
+pub const DEFAULT_SAFE_COMMANDS: &[&str] = &[
+    "GET /**",
+    "POST /element",
+    "POST /elements",
+    "POST /element/*/element",
+    "POST /element/*/elements",
+    "POST /timeouts",
+    "POST /window",
+    "POST /frame",
+    "POST /frame/parent",
+];
+
+/// Longest delay between two tries, whatever the backoff.
+const MAX_DELAY: Duration = Duration::from_secs(5);
+
+/// A request sent again to the hub.
+#[derive(Serialize)]
+pub struct RetryEvent {
+    #[serde(skip)]
+    pub session_id: Option<String>,
+    pub attempt: usize,
+    pub delay_ms: u64,
+    pub reason: String,
+}
+
+impl fmt::Display for RetryEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[REQUEST_RETRIED] [{}] (attempt: {}, delay: {}ms) {}",
+            self.session_id.as_deref().unwrap_or_default(),
+            self.attempt,
+            self.delay_ms,
+            self.reason
+        )
+    }
+}
+
+impl Event for RetryEvent {
+    fn name(&self) -> String {
+        "REQUEST_RETRIED".to_string()
+    }
+
+    fn session_id(&self) -> Option<&str> {
+        self.session_id.as_deref()
+    }
+}
+
+/// When and how a request to the hub is sent again after an error.
+pub struct RetryPolicy {
+    /// Retries of a request after the first try
+    pub max_retries: usize,
+    /// Delay before the first retry, doubled for each next one
+    pub backoff: Duration,
+    /// Time after which a request isn't retried anymore, since its first try
+    pub budget: Duration,
+    /// Statuses of the hub which are retried like an error
+    pub statuses: Vec<StatusCode>,
+    /// The commands which are retried
+    pub commands: Vec<CommandPattern>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 3,
+            backoff: Duration::from_millis(100),
+            budget: Duration::from_secs(10),
+            statuses: vec![StatusCode::BAD_GATEWAY, StatusCode::SERVICE_UNAVAILABLE],
+            commands: DEFAULT_SAFE_COMMANDS
+                .iter()
+                .map(|command| command.parse().unwrap())
+                .collect(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Whether the command can be sent again to the hub.
+    pub fn is_safe(&self, method: &Method, path: &str, base_path: &str) -> bool {
+        self.commands
+            .iter()
+            .any(|command| command.matches(method, path, base_path))
+    }
+
+    pub fn retries_status(&self, status: StatusCode) -> bool {
+        self.statuses.contains(&status)
+    }
+
+    /// Delays before each retry : an exponential backoff, with a full jitter
+    /// so that the clients of a restarting hub don't retry all together.
+    pub fn delays(&self) -> impl Iterator<Item = Duration> {
+        Exponential::from_millis_with_factor(self.backoff.as_millis() as u64, 2.0)
+            .map(|delay| jitter(delay.min(MAX_DELAY)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webdriver::DEFAULT_BASE_PATH;
+
+    #[test]
+    fn is_safe_only_accepts_the_commands_without_side_effect() {
+        let policy = RetryPolicy::default();
+        let is_safe = |method: Method, path: &str| policy.is_safe(&method, path, DEFAULT_BASE_PATH);
+
+        assert!(is_safe(Method::GET, "/wd/hub/session/123/title"));
+        assert!(is_safe(Method::POST, "/wd/hub/session/123/element"));
+        assert!(is_safe(
+            Method::POST,
+            "/wd/hub/session/123/element/456/elements"
+        ));
+        assert!(!is_safe(
+            Method::POST,
+            "/wd/hub/session/123/element/456/click"
+        ));
+        assert!(!is_safe(Method::POST, "/wd/hub/session/123/execute/sync"));
+        assert!(!is_safe(Method::DELETE, "/wd/hub/session/123"));
+        assert!(!is_safe(Method::POST, "/wd/hub/session"));
+    }
+
+    #[test]
+    fn delays_grow_exponentially_until_the_max_delay() {
+        let policy = RetryPolicy {
+            backoff: Duration::from_millis(1000),
+            ..RetryPolicy::default()
+        };
+
+        let delays: Vec<Duration> = policy.delays().take(5).collect();
+
+        assert!(delays[0] <= Duration::from_millis(1000));
+        assert!(delays[1] <= Duration::from_millis(2000));
+        assert!(delays.iter().all(|delay| *delay <= MAX_DELAY));
+    }
+}
@@ -1,87 +1,190 @@
+use crate::auth;
+use crate::body::Prefix;
+use crate::commands::{CommandDoneEvent, CommandEvent};
+use crate::config::Settings;
+use crate::error::ProxyError;
 use crate::inspector;
+use crate::logging::{self, Outcome};
+use crate::metrics::Metrics;
+use crate::registry;
+use crate::retries::RetryEvent;
+use crate::screenshots;
+use crate::timeline::{self, TimelineEntry};
+use crate::webdriver;
+use crate::AppState;
 use bytes::Bytes;
-use hyper::{Body, Method, Request, Response};
+use hyper::body::HttpBody;
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use log::Level;
 use reqwest::Client;
-use std::net::{SocketAddr, ToSocketAddrs};
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::time;
 use url::Url;
 use uuid::Uuid;
 
+/// Only the beginning of the successful responses is read to find the error
+/// of a JSONWP hub, a bigger response (e.g. a screenshot) is a success.
+const MAX_JSONWP_RESPONSE_SIZE: usize = 64 * 1024;
+
 pub struct CapturedRequest<'m, 'b> {
     pub id: Uuid,
     pub url: Url,
     pub method: &'m Method,
     pub path: String,
     pub body: &'b Bytes,
+    /// The authenticated user, if the clients are authenticated
+    pub user: Option<String>,
 }
 
 /// Proxy a Selenium request (from a Selenium client) to the hub.
 /// This function also inspect the content in order to write some logs / insights.
 pub async fn forward(
     req: Request<Body>,
-    state: SocketAddr,
-    timeout: u32,
+    state: Arc<AppState>,
 ) -> Result<Response<Body>, hyper::Error> {
     let request_id = Uuid::new_v4();
-    let forward_url = state.to_string();
-    let out_addr: SocketAddr = forward_url.to_socket_addrs().unwrap().next().unwrap();
+    let started = Instant::now();
+    let _in_flight = state.metrics.in_flight();
+    let settings = state.settings();
     let method = req.method().to_owned();
     let path = &req
         .uri()
         .path_and_query()
         .map(|x| x.to_string())
-        .unwrap_or_else(|| "".to_string());
+        .unwrap_or_default();
 
-    info!("{} {} {}", request_id, method, path);
+    debug!("{} {} {}", request_id, method, path);
 
-    let uri_string = format!("http://{}{}", out_addr, path);
+    let user = match settings.auth.authenticate(req.headers()).await {
+        Ok(user) => user,
+        Err(message) => {
+            warn!("{} refused : {}", request_id, message);
+            return Ok(unauthorized(&message));
+        }
+    };
 
-    let url = Url::parse(&uri_string)
-        .map_err(|err| error!("err : {}", err))
-        .unwrap();
+    // Only the beginning of the body is kept for the inspector, a bigger body
+    // (e.g. a file upload) is streamed to the hub. The bodies nobody reads
+    // are streamed untouched.
+    let command_event = CommandEvent::of(&method, path, &settings.base_path);
+    let command = &command_event.command;
+    let request_body = match body_cap(&method, path, command, &settings) {
+        Some(cap) => Prefix::read(req.into_body(), cap).await?,
+        None if req.body().is_end_stream() => Prefix {
+            bytes: Bytes::new(),
+            rest: None,
+        },
+        None => Prefix {
+            bytes: Bytes::new(),
+            rest: Some(req.into_body()),
+        },
+    };
 
-    let body_bytes = hyper::body::to_bytes(req).await?;
+    let url = match url_of(&settings.forward_uri, path) {
+        Ok(url) => url,
+        Err(err) => {
+            warn!("{} refused : {}", request_id, err);
+            return Ok(err.to_response());
+        }
+    };
 
-    let request_to_inspect = CapturedRequest {
+    let mut request_to_inspect = CapturedRequest {
         id: request_id,
         path: String::from(path),
         url,
         method: &method,
-        body: &body_bytes,
+        body: &request_body.bytes,
+        user,
     };
 
-    inspector::inspect(&request_to_inspect).await;
+    inspector::inspect(&request_to_inspect, &command_event, &state).await;
+
+    let is_a_new_session = inspector::is_a_new_session(path, &settings.base_path);
+
+    // The client may go away while its new session is queued or created
+    let _fail_on_drop = match method == Method::POST && is_a_new_session {
+        true => Some(FailOnDrop {
+            state: &state,
+            request_id,
+        }),
+        false => None,
+    };
+
+    if method == Method::POST && is_a_new_session {
+        if let Err(message) = admit_new_session(&request_to_inspect, &state).await {
+            inspector::reject_new_session(&request_to_inspect, &message, &state);
+            return Ok(webdriver::error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                webdriver::SESSION_NOT_CREATED,
+                &message,
+            ));
+        }
+    }
 
-    let is_a_new_session = inspector::is_a_new_session(&path);
+    let hub = choose_hub(&request_to_inspect, is_a_new_session, &state);
+    request_to_inspect.url = match url_of(&hub, path) {
+        Ok(url) => url,
+        Err(err) => {
+            return Ok(fail(
+                &request_to_inspect,
+                err,
+                &command_event,
+                started,
+                &state,
+            ))
+        }
+    };
 
     // If the request to forward is a create session, we remove the timeout be cause the request is not finished
-    // while it's in the grid queue
-    let client = match is_a_new_session {
-        true => Client::builder()
-            .build()
-            .expect("Can't create the http client."),
-        false => Client::builder()
-            .timeout(Duration::from_secs(timeout.into()))
-            .build()
-            .expect("Can't create the http client."),
+    // while it's in the grid queue. The timeout of the other commands depends on the command and its user.
+    let timeout = match is_a_new_session {
+        true => None,
+        false => {
+            let owner = inspector::session_id_of_path(path.to_owned(), &settings.base_path)
+                .and_then(|session_id| state.sessions.get(&session_id))
+                .map(|session| session.owner)
+                .or_else(|| request_to_inspect.user.to_owned());
+            Some(settings.timeouts.timeout_for(
+                &method,
+                path,
+                &settings.base_path,
+                owner.as_deref(),
+            ))
+        }
     };
 
-    // Send the request with a retry if the request is not a create session
-    // If the last try is an error, the current thread panics
-    let response = send_request(
-        client.to_owned(),
-        request_to_inspect,
-        is_a_new_session,
+    // Send the request, with retries when the command is safe to retry.
+    // If the last try is an error, the client receives a WebDriver error
+    let response = match send_request(
+        &state.client,
+        &request_to_inspect,
+        request_body.rest,
+        timeout.as_ref().map(|timeout| timeout.duration),
+        &settings,
+        &state.metrics,
+        command_event.metric_label(),
     )
     .await
-    .unwrap();
+    {
+        Ok(response) => response,
+        Err(err) => {
+            let err = ProxyError::of_request(&hub, &err, timeout.as_ref());
+            return Ok(fail(
+                &request_to_inspect,
+                err,
+                &command_event,
+                started,
+                &state,
+            ));
+        }
+    };
 
-    if response.status().is_server_error() {
-      error!("Error for request ID {} : {:?}", request_id.to_string(), response);
-    }
+    let status = response.status();
 
     // Rebuild the response by adding the parsed body
-    let mut response_builder = hyper::Response::builder().status(response.status());
+    let mut response_builder = hyper::Response::builder().status(status);
 
     // We copy the headers from the hub response to the client response.
     let headers = response_builder.headers_mut().unwrap();
@@ -90,17 +193,336 @@ pub async fn forward(
         headers.insert(key, value.to_owned());
     }
 
-    // We retrieve the response body as bytes which is useful if we need
-    // to deserialize the data. For example if we need to retrieve the
-    // session id once a session is created on the hub.
-    let response_body = response
-        .bytes()
-        .await
-        .map_err(|err| error!("err for response body unwrap : {}", err))
-        .unwrap();
+    // We read the beginning of the response body when we need to deserialize
+    // it, e.g. to retrieve the session id once a session is created on the hub,
+    // or to log the error of the hub. Any other response is streamed, a
+    // failure in the middle of it can only interrupt the client response.
+    let cap = response_cap(
+        is_a_new_session,
+        &command_event,
+        status,
+        response.headers(),
+        &settings,
+    );
+    let response_body = match cap {
+        Some(cap) => match Prefix::read(response.bytes_stream(), cap).await {
+            Ok(response_body) => response_body,
+            Err(err) => {
+                let err = ProxyError::of_response(&hub, &err, timeout.as_ref());
+                return Ok(fail(
+                    &request_to_inspect,
+                    err,
+                    &command_event,
+                    started,
+                    &state,
+                ));
+            }
+        },
+        None => Prefix {
+            bytes: Bytes::new(),
+            rest: Some(response.bytes_stream()),
+        },
+    };
+
+    let outcome = Outcome {
+        status,
+        duration: started.elapsed(),
+    };
+
+    state.metrics.request_done(
+        command_event.metric_label(),
+        outcome.duration,
+        status.as_u16(),
+    );
+
+    let mut done_event = CommandDoneEvent::new(&command_event, &outcome, &response_body.bytes);
+
+    if status.is_server_error() {
+        let error_event = inspector::ProxyErrorEvent::new(
+            path,
+            &settings.base_path,
+            format!(
+                "the hub answered {} : {}",
+                status,
+                String::from_utf8_lossy(&response_body.bytes)
+            ),
+        );
+        logging::event(
+            Level::Error,
+            &request_to_inspect,
+            &error_event,
+            Some(&outcome),
+        );
+    }
+
+    if method == Method::POST && is_a_new_session {
+        let session_id = inspector::inspect_new_session(
+            &request_to_inspect,
+            &outcome,
+            &response_body.bytes,
+            &state,
+        );
+
+        if let Some(session_id) = session_id {
+            done_event.session_id = Some(session_id.to_owned());
+            tokio::spawn(registry::lookup_node(
+                state.client.to_owned(),
+                hub.to_owned(),
+                session_id,
+                state.sessions.to_owned(),
+            ));
+        }
+    }
+
+    // The screenshot is taken before the client receives the error, so that
+    // it shows the browser as the failed command left it : the error waits up
+    // to SCREENSHOT_TIMEOUT, and the screenshots of a session are limited.
+    if let (Some(dir), Some(session_id)) = (&settings.screenshot_dir, &done_event.session_id) {
+        if screenshots::is_needed_after(&done_event) && state.screenshots.reserve(session_id) {
+            done_event.screenshot = match screenshots::take(
+                &state.client,
+                &hub,
+                path,
+                session_id,
+                request_to_inspect.id,
+                dir,
+            )
+            .await
+            {
+                Ok(file) => Some(file.display().to_string()),
+                Err(err) => {
+                    warn!(
+                        "The screenshot of the session {} failed : {}",
+                        session_id, err
+                    );
+                    None
+                }
+            };
+        }
+    }
+
+    command_done(&request_to_inspect, &done_event, &outcome, &state);
+
+    // A session may have ended, its slot can be given to a queued one
+    state.queue.dispatch(&state.sessions);
 
     // Return the response (from the hub) to the Selenium client.
-    Ok(response_builder.body(Body::from(response_body)).unwrap())
+    let response_body = match response_body.is_complete() {
+        true => Body::from(response_body.bytes),
+        false => Body::wrap_stream(response_body.into_stream()),
+    };
+    Ok(response_builder.body(response_body).unwrap())
+}
+
+/// The client shared by every request to the hubs, which keeps the
+/// connections alive. The timeouts are given by each request.
+pub fn pooled_client(max_idle_per_hub: usize, idle_timeout: Duration) -> Client {
+    Client::builder()
+        .pool_max_idle_per_host(max_idle_per_hub)
+        .pool_idle_timeout(idle_timeout)
+        .build()
+        .expect("Can't create the http client.")
+}
+
+/// How much of the request body is read : the whole body, up to the
+/// inspection cap, when it's inspected (a new session or a page load) or may
+/// be sent again (a retried command), its beginning when it's only kept in
+/// the timeline of the session, none otherwise.
+fn body_cap(method: &Method, path: &str, command: &str, settings: &Settings) -> Option<usize> {
+    let base_path = &settings.base_path;
+    let is_inspected = (method == Method::POST && inspector::is_a_new_session(path, base_path))
+        || command == "navigateTo"
+        || settings.retry.is_safe(method, path, base_path);
+    let is_in_a_timeline = settings.timeline_size > 0
+        && inspector::session_id_of_path(path.to_owned(), base_path).is_some();
+
+    match (is_inspected, is_in_a_timeline) {
+        (true, _) => Some(settings.inspection_size_cap),
+        (false, true) => Some(settings.inspection_size_cap.min(timeline::MAX_PARAMS_SIZE)),
+        (false, false) => None,
+    }
+}
+
+/// How much of the response body is read : up to the inspection cap for a
+/// new session or an error of the hub, the beginning of the other JSON
+/// responses of the commands of a session, since a JSONWP hub answers its
+/// errors with a 200 and a non-zero `status`. Any other response is streamed.
+fn response_cap(
+    is_a_new_session: bool,
+    command: &CommandEvent,
+    status: StatusCode,
+    headers: &HeaderMap,
+    settings: &Settings,
+) -> Option<usize> {
+    let is_json = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.contains("json"));
+
+    match (is_a_new_session || !status.is_success(), is_json) {
+        (true, _) => Some(settings.inspection_size_cap),
+        (false, true) if command.session_id.is_some() => {
+            Some(settings.inspection_size_cap.min(MAX_JSONWP_RESPONSE_SIZE))
+        }
+        (false, _) => None,
+    }
+}
+
+fn unauthorized(message: &str) -> Response<Body> {
+    let mut response =
+        webdriver::error_response(StatusCode::UNAUTHORIZED, webdriver::UNKNOWN_ERROR, message);
+    response.headers_mut().insert(
+        header::WWW_AUTHENTICATE,
+        HeaderValue::from_static(auth::CHALLENGE),
+    );
+    response
+}
+
+fn url_of(hub: &str, path: &str) -> Result<Url, ProxyError> {
+    let uri_string = format!("http://{}{}", hub, path);
+
+    Url::parse(&uri_string)
+        .map_err(|err| ProxyError::InvalidUrl(format!("Invalid URL {} : {}", uri_string, err)))
+}
+
+/// Log the end of the command and add it to the timeline of its session.
+fn command_done<'m, 'b>(
+    request: &CapturedRequest<'m, 'b>,
+    done: &CommandDoneEvent,
+    outcome: &Outcome,
+    state: &AppState,
+) {
+    logging::event(done.level(), request, done, Some(outcome));
+
+    if let Some(session_id) = &done.session_id {
+        state.timelines.record(
+            session_id,
+            TimelineEntry::new(request, done),
+            state.settings().timeline_size,
+        );
+    }
+}
+
+/// The request couldn't be forwarded, or the response of the hub couldn't be
+/// read : the client receives a WebDriver error and a new session is failed.
+fn fail<'m, 'b>(
+    request: &CapturedRequest<'m, 'b>,
+    err: ProxyError,
+    command: &CommandEvent,
+    started: Instant,
+    state: &AppState,
+) -> Response<Body> {
+    let outcome = Outcome {
+        status: err.status(),
+        duration: started.elapsed(),
+    };
+    state.metrics.request_done(
+        command.metric_label(),
+        outcome.duration,
+        outcome.status.as_u16(),
+    );
+
+    let base_path = &state.settings().base_path;
+    let error_event = inspector::ProxyErrorEvent::new(&request.path, base_path, err.to_string());
+    logging::event(Level::Error, request, &error_event, Some(&outcome));
+
+    let done_event = CommandDoneEvent::with_error(command, &outcome, Some(err.error().to_string()));
+    command_done(request, &done_event, &outcome, state);
+
+    if *request.method == Method::POST && inspector::is_a_new_session(&request.path, base_path) {
+        inspector::reject_new_session(request, err.message(), state);
+        state.queue.dispatch(&state.sessions);
+    }
+
+    err.to_response()
+}
+
+/// Refuse the new session while the proxy shuts down, check the identity and
+/// the quota of its owner, then wait for a free slot on the grid. The new
+/// session is already queued, so it is counted in the live sessions of its owner.
+async fn admit_new_session<'m, 'b>(
+    request: &CapturedRequest<'m, 'b>,
+    state: &AppState,
+) -> Result<(), String> {
+    if state.is_shutting_down() {
+        return Err("The proxy is shutting down".to_string());
+    }
+
+    let owner = match state.sessions.get(&request.id.to_string()) {
+        Some(session) => session.owner,
+        None => return Ok(()),
+    };
+
+    if let Some(user) = &request.user {
+        if &owner != user {
+            return Err(format!(
+                "The soda:user {} doesn't match the authenticated user {}",
+                owner, user
+            ));
+        }
+    }
+
+    state
+        .settings()
+        .quotas
+        .check(&owner, &state.sessions.live_sessions_by_owner())?;
+    state
+        .queue
+        .wait_for_a_slot(request, &owner, &state.sessions)
+        .await
+}
+
+/// Fail the new session when its client goes away before the hub answers, so
+/// that it no longer counts in the quota of its owner and in the capacity of
+/// the grid. A session already active or failed is left untouched.
+struct FailOnDrop<'a> {
+    state: &'a AppState,
+    request_id: Uuid,
+}
+
+impl Drop for FailOnDrop<'_> {
+    fn drop(&mut self) {
+        if let Some(session) = self.state.sessions.fail(self.request_id) {
+            warn!(
+                "{} failed : the client went away before the session was created",
+                self.request_id
+            );
+            self.state
+                .metrics
+                .session_changed(session.status, &session.capabilities);
+            self.state.queue.dispatch(&self.state.sessions);
+        }
+    }
+}
+
+/// Choose the hub of the request. A new session goes to a hub of the pool of
+/// the first route matching its capabilities (or of the default pool), then
+/// every command of the session goes to the hub which created it.
+/// Otherwise the first default hub is used.
+fn choose_hub(request: &CapturedRequest, is_a_new_session: bool, state: &AppState) -> String {
+    let settings = state.settings();
+    if *request.method == Method::POST && is_a_new_session {
+        let request_id = request.id.to_string();
+        let active_sessions = state.sessions.active_sessions_by_hub();
+        let hub = state
+            .sessions
+            .get(&request_id)
+            .and_then(|session| {
+                settings
+                    .router
+                    .hub_for(&session.capabilities, &active_sessions)
+                    .map(String::from)
+            })
+            .unwrap_or_else(|| settings.forward_uri.to_owned());
+
+        state.sessions.set_hub(&request_id, &hub);
+        return hub;
+    }
+
+    inspector::session_id_of_path(request.path.to_owned(), &settings.base_path)
+        .and_then(|session_id| state.sessions.get(&session_id))
+        .and_then(|session| session.hub)
+        .unwrap_or_else(|| settings.forward_uri.to_owned())
 }
 
 // Recreate a request based on the client http request.
@@ -108,34 +530,442 @@ pub async fn forward(
 // Example : POST /session
 // the path is /session, the method is POST and the data is the request body (bytes).
 // Then we send the the request to the hub and we retrieve the response asynchronously.
+// The body is the captured one, followed by `rest` when it was too big to be fully read.
 pub async fn send_request<'m, 'b>(
-    client: Client,
-    request_to_inspect: CapturedRequest<'m, 'b>,
-    is_a_new_session: bool,
-) -> Result<reqwest::Response, String> {
-    let mut tries: usize = 1;
-    // We retry the request 3 times (excepted for the new session) in case there is a timeout.
+    client: &Client,
+    request_to_inspect: &CapturedRequest<'m, 'b>,
+    mut rest: Option<Body>,
+    timeout: Option<Duration>,
+    settings: &Settings,
+    metrics: &Metrics,
+    command: &str,
+) -> Result<reqwest::Response, reqwest::Error> {
+    let policy = &settings.retry;
+    // A streamed body can't be sent twice, and a command with side effects
+    // (e.g. a click) could be executed twice by the hub.
+    let is_retryable = rest.is_none()
+        && policy.is_safe(
+            request_to_inspect.method,
+            &request_to_inspect.path,
+            &settings.base_path,
+        );
+    let started = Instant::now();
+    let mut delays = policy.delays();
+    let mut attempt = 0;
+
     loop {
-        let response = client
+        let body = match rest.take() {
+            Some(rest) => {
+                let body = Prefix {
+                    bytes: request_to_inspect.body.to_owned(),
+                    rest: Some(rest),
+                };
+                reqwest::Body::wrap_stream(body.into_stream())
+            }
+            None => reqwest::Body::from(request_to_inspect.body.to_owned()),
+        };
+        let mut request = client
             .request(
                 request_to_inspect.method.to_owned(),
                 request_to_inspect.url.to_owned(),
             )
-            .body(request_to_inspect.body.to_vec())
-            .send()
-            .await
-            .map_err(|err| {
-                format!(
-                    "Request Id : {} Try number {} in error for response unwrap : {}",
-                    request_to_inspect.id, tries, err
-                )
-            });
-        match response {
-            Err(e) if tries <= 3 && !is_a_new_session => {
-                tries += 1;
-                log::error!("{}", e);
+            .body(body);
+        if let Some(timeout) = timeout {
+            request = request.timeout(timeout);
+        }
+
+        let response = request.send().await;
+        let reason = match &response {
+            Err(err) => err.to_string(),
+            Ok(response) if policy.retries_status(response.status()) => {
+                format!("the hub answered {}", response.status())
+            }
+            Ok(_) => return response,
+        };
+
+        let delay = delays.next().unwrap_or_default();
+        if !is_retryable
+            || attempt >= policy.max_retries
+            || started.elapsed() + delay > policy.budget
+        {
+            return response;
+        }
+
+        attempt += 1;
+        metrics.retried(command);
+        let retry_event = RetryEvent {
+            session_id: inspector::session_id_of_path(
+                request_to_inspect.path.to_owned(),
+                &settings.base_path,
+            ),
+            attempt,
+            delay_ms: delay.as_millis() as u64,
+            reason,
+        };
+        logging::event(Level::Warn, request_to_inspect, &retry_event, None);
+
+        time::delay_for(delay).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+    use crate::domain::{DesiredCapabilities, SessionStatus};
+    use crate::quotas::Quotas;
+    use crate::timeouts::Timeouts;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::Server;
+    use std::convert::Infallible;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    /// A hub answering every command, which counts the connections it accepts.
+    fn stub_hub() -> (SocketAddr, Arc<AtomicUsize>) {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+
+        let make_svc = make_service_fn(move |_| {
+            accepted.fetch_add(1, Ordering::SeqCst);
+            async {
+                Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                    let body = match inspector::is_a_new_session(
+                        req.uri().path(),
+                        webdriver::DEFAULT_BASE_PATH,
+                    ) {
+                        true => format!("{{\"value\":{{\"sessionId\":\"{}\"}}}}", Uuid::new_v4()),
+                        false => "{\"value\":null}".to_string(),
+                    };
+                    Ok::<_, Infallible>(Response::new(Body::from(body)))
+                }))
             }
-            res => break res,
+        });
+
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+        let address = server.local_addr();
+        tokio::spawn(server);
+
+        (address, connections)
+    }
+
+    async fn send(state: &Arc<AppState>, method: Method, path: &str) -> StatusCode {
+        let req = Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Body::from("{}"))
+            .unwrap();
+        let response = forward(req, state.clone()).await.unwrap();
+        let status = response.status();
+        hyper::body::to_bytes(response.into_body()).await.unwrap();
+        status
+    }
+
+    /// A hub answering its first requests with a 503, which counts the requests.
+    fn unavailable_hub(failures: usize) -> (SocketAddr, Arc<AtomicUsize>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let received = requests.clone();
+
+        let make_svc = make_service_fn(move |_| {
+            let received = received.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |_: Request<Body>| {
+                    let status = match received.fetch_add(1, Ordering::SeqCst) < failures {
+                        true => StatusCode::SERVICE_UNAVAILABLE,
+                        false => StatusCode::OK,
+                    };
+                    async move {
+                        let mut response = Response::new(Body::from("{\"value\":null}"));
+                        *response.status_mut() = status;
+                        Ok::<_, Infallible>(response)
+                    }
+                }))
+            }
+        });
+
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+        let address = server.local_addr();
+        tokio::spawn(server);
+
+        (address, requests)
+    }
+
+    /// A hub which answers `response` then closes the connection, or keeps
+    /// the connections open without answering when there's no response.
+    fn raw_hub(response: Option<&'static str>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let mut connections = vec![];
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                match response {
+                    Some(response) => {
+                        let mut request = [0; 4096];
+                        let _ = stream.read(&mut request);
+                        let _ = stream.write_all(response.as_bytes());
+                    }
+                    None => connections.push(stream),
+                }
+            }
+        });
+
+        address
+    }
+
+    /// The status and the WebDriver error of the response.
+    async fn send_for_error(
+        state: &Arc<AppState>,
+        method: Method,
+        path: &str,
+    ) -> (StatusCode, serde_json::Value) {
+        let req = Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Body::from("{}"))
+            .unwrap();
+        let response = forward(req, state.clone()).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        (status, body["value"].to_owned())
+    }
+
+    #[test]
+    fn body_cap_only_reads_the_bodies_inspected_or_kept() {
+        let mut settings = Settings::new("127.0.0.1:4444".to_string(), 60);
+        let cap = settings.inspection_size_cap;
+        let body_cap = |method: Method, path: &str, settings: &Settings| {
+            let command = CommandEvent::of(&method, path, &settings.base_path).command;
+            body_cap(&method, path, &command, settings)
+        };
+
+        assert_eq!(
+            body_cap(Method::POST, "/wd/hub/session", &settings),
+            Some(cap)
+        );
+        assert_eq!(
+            body_cap(Method::POST, "/session/123/url", &settings),
+            Some(cap)
+        );
+        assert_eq!(
+            body_cap(Method::POST, "/session/123/element", &settings),
+            Some(cap)
+        );
+        assert_eq!(
+            body_cap(Method::POST, "/session/123/se/file", &settings),
+            Some(timeline::MAX_PARAMS_SIZE)
+        );
+
+        settings.timeline_size = 0;
+        assert_eq!(
+            body_cap(Method::POST, "/session/123/se/file", &settings),
+            None
+        );
+        assert_eq!(
+            body_cap(Method::POST, "/session/123/execute/sync", &settings),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn forward_answers_a_bad_gateway_when_the_hub_is_unreachable() {
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let state = Arc::new(AppState::new(closed.to_string(), 60));
+
+        let (status, error) =
+            send_for_error(&state, Method::GET, "/wd/hub/session/123/title").await;
+
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(error["error"], webdriver::UNKNOWN_ERROR);
+    }
+
+    #[tokio::test]
+    async fn forward_answers_a_gateway_timeout_when_the_hub_is_too_slow() {
+        let hub = raw_hub(None);
+        let state = Arc::new(AppState::new(hub.to_string(), 1));
+
+        let (status, error) =
+            send_for_error(&state, Method::GET, "/wd/hub/session/123/title").await;
+
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(error["error"], webdriver::TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn forward_reports_the_timeout_rule_of_the_command() {
+        let hub = raw_hub(None);
+        let mut settings = Settings::new(hub.to_string(), 60);
+        settings.timeouts = Timeouts::new(
+            Duration::from_secs(60),
+            vec!["GET /title=1".parse().unwrap()],
+        );
+        settings.retry.max_retries = 0;
+        let state = Arc::new(AppState::with_settings(settings));
+
+        let (status, error) =
+            send_for_error(&state, Method::GET, "/wd/hub/session/123/title").await;
+
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+        assert!(error["message"]
+            .as_str()
+            .unwrap()
+            .contains("1s (rule GET /title=1)"));
+    }
+
+    #[test]
+    fn response_cap_reads_the_json_responses_of_the_commands() {
+        let settings = Settings::new("127.0.0.1:4444".to_string(), 60);
+        let cap = settings.inspection_size_cap;
+        let json = |content_type: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+            headers
+        };
+        let response_cap = |path: &str, status: StatusCode, headers: &HeaderMap| {
+            let command = CommandEvent::of(&Method::GET, path, &settings.base_path);
+            response_cap(
+                inspector::is_a_new_session(path, &settings.base_path),
+                &command,
+                status,
+                headers,
+                &settings,
+            )
+        };
+
+        assert_eq!(
+            response_cap(
+                "/session/123/title",
+                StatusCode::NOT_FOUND,
+                &HeaderMap::new()
+            ),
+            Some(cap)
+        );
+        assert_eq!(
+            response_cap(
+                "/session/123/title",
+                StatusCode::OK,
+                &json("application/json; charset=utf-8")
+            ),
+            Some(MAX_JSONWP_RESPONSE_SIZE)
+        );
+        assert_eq!(
+            response_cap("/session/123/title", StatusCode::OK, &json("text/plain")),
+            None
+        );
+        assert_eq!(
+            response_cap("/status", StatusCode::OK, &json("application/json")),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn forward_reads_the_jsonwp_error_of_a_successful_response() {
+        let hub = raw_hub(Some(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 68\r\n\r\n{\"sessionId\":\"123\",\"status\":7,\"value\":{\"message\":\"no such element\"}}",
+        ));
+        let state = Arc::new(AppState::new(hub.to_string(), 60));
+
+        let status = send(&state, Method::POST, "/wd/hub/session/123/element").await;
+
+        assert_eq!(status, StatusCode::OK);
+        let timeline = state.timelines.get("123").unwrap();
+        assert_eq!(timeline[0].error.as_deref(), Some("no such element"));
+    }
+
+    #[tokio::test]
+    async fn forward_fails_the_new_session_when_the_response_is_truncated() {
+        let hub = raw_hub(Some(
+            "HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n{\"value\":{\"sessionId\"",
+        ));
+        let state = Arc::new(AppState::new(hub.to_string(), 60));
+
+        let (status, error) = send_for_error(&state, Method::POST, "/wd/hub/session").await;
+
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(error["error"], webdriver::UNKNOWN_ERROR);
+        let sessions = state.sessions.list();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].status, SessionStatus::Failed);
+    }
+
+    #[tokio::test]
+    async fn forward_frees_the_slot_and_the_quota_when_the_client_goes_away() {
+        let hub = raw_hub(None);
+        let mut settings = Settings::new(hub.to_string(), 60);
+        settings.grid_capacity = Some(1);
+        settings.queue_timeout = Duration::from_secs(60);
+        settings.quotas = Quotas::new(vec!["GUEST=1".parse().unwrap()], vec![]);
+        let state = Arc::new(AppState::with_settings(settings));
+        let new_session = || {
+            tokio::time::timeout(
+                Duration::from_millis(100),
+                send(&state, Method::POST, "/wd/hub/session"),
+            )
+        };
+
+        // The hub never answers, the client goes away while the session is created
+        assert!(new_session().await.is_err());
+        assert_eq!(state.sessions.opened_sessions(), 0);
+        assert_eq!(state.sessions.live_sessions_by_owner().get("GUEST"), None);
+
+        // The grid is full, the client goes away while the session is queued
+        let capabilities = DesiredCapabilities {
+            soda_user: Some("team-a".to_string()),
+            ..DesiredCapabilities::default()
+        };
+        state
+            .sessions
+            .activate(Uuid::new_v4(), "busy", capabilities);
+        assert!(new_session().await.is_err());
+        assert_eq!(state.queue.snapshot().depth, 0);
+        assert_eq!(state.sessions.opened_sessions(), 1);
+        assert_eq!(state.sessions.live_sessions_by_owner().get("GUEST"), None);
+    }
+
+    #[tokio::test]
+    async fn forward_retries_a_safe_command_when_the_hub_is_unavailable() {
+        let (hub, requests) = unavailable_hub(2);
+        let state = Arc::new(AppState::new(hub.to_string(), 60));
+
+        let status = send(&state, Method::GET, "/wd/hub/session/123/title").await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn forward_never_retries_a_command_with_side_effects() {
+        let (hub, requests) = unavailable_hub(2);
+        let state = Arc::new(AppState::new(hub.to_string(), 60));
+
+        let status = send(
+            &state,
+            Method::POST,
+            "/wd/hub/session/123/element/456/click",
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn forward_reuses_the_connections_to_the_hub() {
+        let (hub, connections) = stub_hub();
+        let state = Arc::new(AppState::new(hub.to_string(), 60));
+
+        for _ in 0..20 {
+            let status = send(&state, Method::GET, "/wd/hub/session/123/title").await;
+            assert_eq!(status, StatusCode::OK);
         }
+
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
     }
 }
@@ -0,0 +1,252 @@
+use crate::balancing::{Balancing, Hub, HubAddress, HubPool};
+use crate::domain::DesiredCapabilities;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// The capabilities a route can match, besides the `soda:*` ones.
+const MATCHERS: &[&str] = &[
+    "browserName",
+    "browserVersion",
+    "version",
+    "platformName",
+    "platform",
+];
+
+/// Prefix of the vendor capabilities a route can match.
+const SODA_PREFIX: &str = "soda:";
+
+/// Send the new sessions matching some capabilities to a pool of hubs.
+/// Format : `CAPABILITY=VALUE[,CAPABILITY=VALUE...]@IP:PORT[*WEIGHT][,IP:PORT[*WEIGHT]...]`,
+/// e.g. `browserName=firefox,soda:network=internal@10.0.0.2:4444,10.0.0.3:4444`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Route {
+    pub matchers: Vec<(String, String)>,
+    pub hubs: Vec<HubAddress>,
+}
+
+impl FromStr for Route {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (matchers, hubs) = s
+            .rsplit_once('@')
+            .ok_or_else(|| format!("Route {} : format must be CAPABILITY=VALUE@IP:PORT", s))?;
+
+        let hubs = hubs
+            .split(',')
+            .map(|hub| {
+                hub.parse::<HubAddress>()
+                    .map_err(|err| format!("Route {} : {}", s, err))
+            })
+            .collect::<Result<Vec<HubAddress>, String>>()?;
+
+        let matchers = matchers
+            .split(',')
+            .map(|matcher| match matcher.split_once('=') {
+                Some((name, value)) if !name.is_empty() => {
+                    let name = name.trim();
+                    match MATCHERS.contains(&name) || name.starts_with(SODA_PREFIX) {
+                        true => Ok((name.to_string(), value.trim().to_string())),
+                        false => Err(format!(
+                            "Route {} : unknown capability {} ({} or soda:*)",
+                            s,
+                            name,
+                            MATCHERS.join(", ")
+                        )),
+                    }
+                }
+                _ => Err(format!(
+                    "Route {} : {} must be CAPABILITY=VALUE",
+                    s, matcher
+                )),
+            })
+            .collect::<Result<Vec<(String, String)>, String>>()?;
+
+        Ok(Route { matchers, hubs })
+    }
+}
+
+impl Route {
+    /// Every matcher must match the capabilities. Browser and platform are
+    /// compared ignoring the case, vendor capabilities must be equal.
+    pub fn matches(&self, capabilities: &DesiredCapabilities) -> bool {
+        self.matchers.iter().all(|(name, expected)| {
+            let actual = match name.as_str() {
+                "browserName" => capabilities.browser_name.as_ref(),
+                "browserVersion" | "version" => capabilities.browser_version.as_ref(),
+                "platformName" | "platform" => capabilities.platform.as_ref(),
+                vendor => capabilities.soda.get(vendor),
+            };
+
+            match actual {
+                Some(actual) if name.starts_with(SODA_PREFIX) => actual == expected,
+                Some(actual) => actual.eq_ignore_ascii_case(expected),
+                None => false,
+            }
+        })
+    }
+}
+
+/// Choose the hub of the new sessions : the pool of the first matching
+/// route, or the default pool, then a hub of the pool.
+pub struct Router {
+    routes: Vec<(Route, HubPool)>,
+    default_pool: HubPool,
+    balancing: Balancing,
+}
+
+impl Router {
+    pub fn new(default_hubs: Vec<HubAddress>, routes: Vec<Route>, balancing: Balancing) -> Router {
+        Router {
+            routes: routes
+                .into_iter()
+                .map(|route| {
+                    let pool = HubPool::new(route.hubs.to_owned());
+                    (route, pool)
+                })
+                .collect(),
+            default_pool: HubPool::new(default_hubs),
+            balancing,
+        }
+    }
+
+    /// `active_sessions` is the number of active sessions by hub address.
+    pub fn hub_for(
+        &self,
+        capabilities: &DesiredCapabilities,
+        active_sessions: &HashMap<String, usize>,
+    ) -> Option<&str> {
+        let pool = self
+            .routes
+            .iter()
+            .find(|(route, _)| route.matches(capabilities))
+            .map(|(_, pool)| pool)
+            .unwrap_or(&self.default_pool);
+
+        pool.pick(self.balancing, active_sessions)
+    }
+
+    /// Whether the sessions are shared between several hubs somewhere.
+    pub fn has_a_pool(&self) -> bool {
+        self.default_pool.hubs().len() > 1
+            || self.routes.iter().any(|(_, pool)| pool.hubs().len() > 1)
+    }
+
+    /// Keep the health of the hubs already known by the previous router.
+    pub fn keep_health_of(&self, previous: &Router) {
+        let previous_hubs = previous.hubs();
+        for hub in self.hubs() {
+            if let Some(known) = previous_hubs
+                .iter()
+                .find(|known| known.address == hub.address)
+            {
+                hub.keep_health_of(known);
+            }
+        }
+    }
+
+    /// Every hub known by the router, a hub may belong to several pools.
+    pub fn hubs(&self) -> Vec<&Hub> {
+        self.default_pool
+            .hubs()
+            .iter()
+            .chain(self.routes.iter().flat_map(|(_, pool)| pool.hubs()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capabilities(browser: &str, network: Option<&str>) -> DesiredCapabilities {
+        let mut capabilities = DesiredCapabilities {
+            browser_name: Some(browser.to_string()),
+            platform: Some("LINUX".to_string()),
+            ..DesiredCapabilities::default()
+        };
+        if let Some(network) = network {
+            capabilities
+                .soda
+                .insert("soda:network".to_string(), network.to_string());
+        }
+        capabilities
+    }
+
+    #[test]
+    fn route_is_parsed_from_the_cli_format() {
+        let route: Route =
+            "browserName=firefox,soda:network=internal@10.0.0.2:4444,10.0.0.3:4444*2"
+                .parse()
+                .unwrap();
+
+        assert_eq!(
+            route,
+            Route {
+                matchers: vec![
+                    ("browserName".to_string(), "firefox".to_string()),
+                    ("soda:network".to_string(), "internal".to_string()),
+                ],
+                hubs: vec![
+                    HubAddress {
+                        address: "10.0.0.2:4444".to_string(),
+                        weight: 1
+                    },
+                    HubAddress {
+                        address: "10.0.0.3:4444".to_string(),
+                        weight: 2
+                    },
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn route_parsing_fails_when_the_format_is_invalid() {
+        assert!("browserName=firefox".parse::<Route>().is_err());
+        assert!("browserName@10.0.0.2:4444".parse::<Route>().is_err());
+        assert!("browserName=firefox@10.0.0.2".parse::<Route>().is_err());
+        assert!("browsername=firefox@10.0.0.2:4444"
+            .parse::<Route>()
+            .is_err());
+        assert!("network=internal@10.0.0.2:4444".parse::<Route>().is_err());
+    }
+
+    #[test]
+    fn hub_for_returns_a_hub_of_the_first_matching_route() {
+        let router = Router::new(
+            vec!["default:4444".parse().unwrap()],
+            vec![
+                "browserName=firefox,soda:network=internal@internal-firefox:4444"
+                    .parse()
+                    .unwrap(),
+                "browserName=FIREFOX@firefox:4444".parse().unwrap(),
+                "platformName=linux@linux:4444".parse().unwrap(),
+            ],
+            Balancing::RoundRobin,
+        );
+        let hub_for = |capabilities: DesiredCapabilities| {
+            router
+                .hub_for(&capabilities, &HashMap::new())
+                .map(String::from)
+        };
+
+        assert_eq!(
+            hub_for(capabilities("firefox", Some("internal"))),
+            Some("internal-firefox:4444".to_string())
+        );
+        assert_eq!(
+            hub_for(capabilities("firefox", Some("external"))),
+            Some("firefox:4444".to_string())
+        );
+        assert_eq!(
+            hub_for(capabilities("chrome", None)),
+            Some("linux:4444".to_string())
+        );
+        assert_eq!(
+            hub_for(DesiredCapabilities::default()),
+            Some("default:4444".to_string())
+        );
+        assert_eq!(router.hubs().len(), 4);
+    }
+}
@@ -0,0 +1,303 @@
+use crate::commands::CommandDoneEvent;
+use crate::AppState;
+use reqwest::Client;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::task;
+use tokio::time;
+use uuid::Uuid;
+
+/// Timeout of the screenshots asked by the proxy to the hub, the response of
+/// the failed command waits for it.
+pub const SCREENSHOT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Minimum interval between two screenshots of a session, e.g. while the
+/// client polls an element which isn't there yet.
+pub const MIN_SCREENSHOT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Screenshots taken by session at most.
+pub const MAX_SCREENSHOTS_BY_SESSION: usize = 50;
+
+/// How long the screenshots are kept by default, in seconds (a week).
+pub const DEFAULT_RETENTION: u64 = 7 * 24 * 3600;
+
+/// How many sessions are remembered for the limits, the least recently
+/// screenshotted ones are forgotten like the timelines.
+const MAX_SESSIONS: usize = 1000;
+
+/// Interval between two deletions of the expired screenshots.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(600);
+
+/// The commands without a screenshot when they fail : the session is gone,
+/// or the screenshot itself failed.
+const WITHOUT_SCREENSHOT: &[&str] = &["deleteSession", "takeScreenshot", "takeElementScreenshot"];
+
+/// The error of the commands of an unknown or deleted session.
+const INVALID_SESSION_ID: &str = "invalid session id";
+
+#[derive(Deserialize)]
+struct ScreenshotResponse {
+    value: String,
+}
+
+/// Whether a screenshot of the session should be taken after the command :
+/// the hub answered a WebDriver error to a command of a session.
+pub fn is_needed_after(done: &CommandDoneEvent) -> bool {
+    done.session_id.is_some()
+        && done.error.is_some()
+        && done.error.as_deref() != Some(INVALID_SESSION_ID)
+        && !WITHOUT_SCREENSHOT.contains(&done.command.as_str())
+}
+
+struct Taken {
+    last: Instant,
+    count: usize,
+}
+
+/// The screenshots taken by session, to limit them.
+#[derive(Default)]
+pub struct Screenshots {
+    taken: Mutex<HashMap<String, Taken>>,
+}
+
+impl Screenshots {
+    pub fn new() -> Screenshots {
+        Screenshots::default()
+    }
+
+    /// Whether a screenshot of the session can be taken now, it's then
+    /// counted : one every `MIN_SCREENSHOT_INTERVAL` and
+    /// `MAX_SCREENSHOTS_BY_SESSION` at most.
+    pub fn reserve(&self, session_id: &str) -> bool {
+        self.reserve_at(session_id, Instant::now())
+    }
+
+    fn reserve_at(&self, session_id: &str, now: Instant) -> bool {
+        let mut taken = self.taken.lock().unwrap();
+        if let Some(session) = taken.get_mut(session_id) {
+            if session.count >= MAX_SCREENSHOTS_BY_SESSION
+                || now.saturating_duration_since(session.last) < MIN_SCREENSHOT_INTERVAL
+            {
+                return false;
+            }
+            session.last = now;
+            session.count += 1;
+            return true;
+        }
+
+        taken.insert(
+            session_id.to_string(),
+            Taken {
+                last: now,
+                count: 1,
+            },
+        );
+        if taken.len() > MAX_SESSIONS {
+            let oldest = taken
+                .iter()
+                .min_by_key(|(_, session)| session.last)
+                .map(|(session_id, _)| session_id.to_owned());
+            if let Some(oldest) = oldest {
+                taken.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+/// Take a screenshot of the session on its hub, saved as
+/// `DIR/SESSION_ID/REQUEST_ID.png`. The screenshot is asked under the prefix
+/// of the failed command, e.g. `/wd/hub`.
+pub async fn take(
+    client: &Client,
+    hub: &str,
+    path: &str,
+    session_id: &str,
+    request_id: Uuid,
+    dir: &Path,
+) -> Result<PathBuf, String> {
+    // The session id names the directory of the screenshot
+    if !session_id
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(format!("invalid session id {}", session_id));
+    }
+
+    let session_path = format!("/session/{}", session_id);
+    let prefix = path
+        .find(&session_path)
+        .map(|index| &path[..index])
+        .unwrap_or_default();
+    let url = format!("http://{}{}{}/screenshot", hub, prefix, session_path);
+
+    let response = client
+        .get(&url)
+        .timeout(SCREENSHOT_TIMEOUT)
+        .send()
+        .await
+        .map_err(|err| err.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("the hub answered {}", response.status()));
+    }
+    let body = response.bytes().await.map_err(|err| err.to_string())?;
+    let screenshot: ScreenshotResponse =
+        serde_json::from_slice(&body).map_err(|err| err.to_string())?;
+    let png = base64::decode(&screenshot.value).map_err(|err| err.to_string())?;
+
+    let session_dir = dir.join(session_id);
+    let file = session_dir.join(format!("{}.png", request_id));
+    let written = file.to_owned();
+    task::spawn_blocking(move || {
+        fs::create_dir_all(&session_dir).and_then(|_| fs::write(&written, png))
+    })
+    .await
+    .map_err(|err| err.to_string())?
+    .map_err(|err| format!("{} : {}", file.display(), err))?;
+
+    Ok(file)
+}
+
+/// Periodically delete the screenshots older than the retention of the
+/// settings, then the emptied session directories.
+pub async fn run_cleanup(state: Arc<AppState>) {
+    loop {
+        let settings = state.settings();
+        if let Some(dir) = settings.screenshot_dir.to_owned() {
+            let retention = settings.screenshot_retention;
+            match task::spawn_blocking(move || remove_expired(&dir, retention)).await {
+                Ok(Ok(0)) => {}
+                Ok(Ok(count)) => info!("{} expired screenshots deleted", count),
+                Ok(Err(err)) => warn!("The expired screenshots can't be deleted : {}", err),
+                Err(err) => warn!("The expired screenshots can't be deleted : {}", err),
+            }
+        }
+
+        time::delay_for(CLEANUP_INTERVAL).await;
+    }
+}
+
+/// Delete the screenshots of `DIR/SESSION_ID/` modified before the
+/// retention, the other files are left untouched.
+fn remove_expired(dir: &Path, retention: Duration) -> io::Result<usize> {
+    if !dir.is_dir() {
+        return Ok(0);
+    }
+    let expiry = SystemTime::now()
+        .checked_sub(retention)
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+
+    let mut count = 0;
+    for session_dir in fs::read_dir(dir)? {
+        let session_dir = session_dir?.path();
+        if !session_dir.is_dir() {
+            continue;
+        }
+        let previous_count = count;
+        for file in fs::read_dir(&session_dir)? {
+            let file = file?.path();
+            let is_expired = fs::metadata(&file)
+                .and_then(|metadata| metadata.modified())
+                .map(|modified| modified < expiry)
+                .unwrap_or(false);
+            if file.extension() == Some("png".as_ref()) && is_expired {
+                fs::remove_file(&file)?;
+                count += 1;
+            }
+        }
+        // Only a directory emptied of its screenshots is removed
+        if count > previous_count {
+            let _ = fs::remove_dir(&session_dir);
+        }
+    }
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::CommandEvent;
+    use crate::logging::Outcome;
+    use crate::webdriver::DEFAULT_BASE_PATH;
+    use hyper::{Method, StatusCode};
+
+    fn done(method: Method, path: &str, body: &[u8]) -> CommandDoneEvent {
+        let outcome = Outcome {
+            status: StatusCode::NOT_FOUND,
+            duration: Duration::from_millis(5),
+        };
+        CommandDoneEvent::new(
+            &CommandEvent::of(&method, path, DEFAULT_BASE_PATH),
+            &outcome,
+            body,
+        )
+    }
+
+    #[test]
+    fn is_needed_after_a_webdriver_error_of_a_session() {
+        let error = br#"{"value":{"error":"no such element","message":""}}"#;
+
+        assert!(is_needed_after(&done(
+            Method::POST,
+            "/session/123/element",
+            error
+        )));
+        assert!(!is_needed_after(&done(
+            Method::POST,
+            "/session/123/element",
+            br#"{"value":{"ELEMENT":"456"}}"#
+        )));
+        assert!(!is_needed_after(&done(
+            Method::GET,
+            "/session/123/screenshot",
+            error
+        )));
+        assert!(!is_needed_after(&done(Method::POST, "/session", error)));
+        assert!(!is_needed_after(&done(
+            Method::GET,
+            "/session/123/title",
+            br#"{"value":{"error":"invalid session id","message":""}}"#
+        )));
+    }
+
+    #[test]
+    fn reserve_limits_the_screenshots_of_a_session() {
+        let screenshots = Screenshots::new();
+        let now = Instant::now();
+
+        assert!(screenshots.reserve_at("123", now));
+        assert!(!screenshots.reserve_at("123", now + MIN_SCREENSHOT_INTERVAL / 2));
+        assert!(screenshots.reserve_at("456", now));
+        assert!(screenshots.reserve_at("123", now + MIN_SCREENSHOT_INTERVAL));
+
+        for i in 2..MAX_SCREENSHOTS_BY_SESSION as u32 {
+            assert!(screenshots.reserve_at("123", now + MIN_SCREENSHOT_INTERVAL * i));
+        }
+        assert!(!screenshots.reserve_at("123", now + MIN_SCREENSHOT_INTERVAL * 1000));
+    }
+
+    #[test]
+    fn remove_expired_only_deletes_the_old_screenshots() {
+        let dir = std::env::temp_dir().join(format!("screenshots-{}", Uuid::new_v4()));
+        let session_dir = dir.join("123");
+        fs::create_dir_all(&session_dir).unwrap();
+        fs::write(session_dir.join("1.png"), b"png").unwrap();
+        fs::write(session_dir.join("notes.txt"), b"notes").unwrap();
+
+        assert_eq!(remove_expired(&dir, Duration::from_secs(3600)).unwrap(), 0);
+        assert!(session_dir.join("1.png").exists());
+
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(remove_expired(&dir, Duration::from_millis(1)).unwrap(), 1);
+        assert!(!session_dir.join("1.png").exists());
+        assert!(session_dir.join("notes.txt").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(remove_expired(&dir, Duration::from_millis(1)).unwrap(), 0);
+    }
+}
@@ -0,0 +1,259 @@
+use crate::domain::{Session, SessionStatus};
+use crate::logging::{self, Event};
+use crate::reaper;
+use crate::AppState;
+use chrono::Utc;
+use futures::future;
+use log::Level;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use tokio::signal::unix::{signal, SignalKind};
+
+/// What is done with the sessions still open when the proxy stops.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OpenSessions {
+    /// The sessions stay open on the hubs
+    Keep,
+    /// The sessions are deleted on the hubs, which frees their browsers
+    Delete,
+    /// The sessions stay open on the hubs and are written to the hand-off
+    /// file, the next proxy started with this file forwards their commands
+    Handoff,
+}
+
+impl FromStr for OpenSessions {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "keep" => Ok(OpenSessions::Keep),
+            "delete" => Ok(OpenSessions::Delete),
+            "handoff" => Ok(OpenSessions::Handoff),
+            _ => Err(format!(
+                "Unknown action on the open sessions : {} (keep, delete or handoff)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for OpenSessions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OpenSessions::Keep => write!(f, "kept"),
+            OpenSessions::Delete => write!(f, "deleted"),
+            OpenSessions::Handoff => write!(f, "handed off"),
+        }
+    }
+}
+
+/// A session still open when the proxy stopped.
+#[derive(Serialize)]
+struct OpenSession {
+    session_id: String,
+    owner: String,
+    hub: Option<String>,
+    age_secs: i64,
+    command_count: u64,
+    /// Whether the session was deleted, or handed off, as asked
+    closed: bool,
+}
+
+impl fmt::Display for OpenSession {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} [{}] on {} ({}s, {} commands)",
+            self.session_id,
+            self.owner,
+            self.hub.as_deref().unwrap_or("the default hub"),
+            self.age_secs,
+            self.command_count
+        )
+    }
+}
+
+/// The summary of the shutdown.
+#[derive(Serialize)]
+struct ShutdownEvent {
+    signal: String,
+    /// Whether every request in flight finished before the deadline
+    drained: bool,
+    aborted_requests: i64,
+    action: String,
+    open_sessions: Vec<OpenSession>,
+}
+
+impl fmt::Display for ShutdownEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[PROXY_SHUTDOWN] ({}, drained: {}, aborted requests: {}) {} open sessions {}",
+            self.signal,
+            self.drained,
+            self.aborted_requests,
+            self.open_sessions.len(),
+            self.action
+        )?;
+        for session in &self.open_sessions {
+            write!(f, ", {}", session)?;
+        }
+        Ok(())
+    }
+}
+
+impl Event for ShutdownEvent {
+    fn name(&self) -> String {
+        "PROXY_SHUTDOWN".to_string()
+    }
+}
+
+/// Wait for SIGTERM (e.g. a container stopped) or SIGINT (Ctrl+C).
+pub async fn signal_received() -> &'static str {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(err) => {
+            error!("SIGTERM can't be handled : {}", err);
+            let _ = tokio::signal::ctrl_c().await;
+            return "SIGINT";
+        }
+    };
+
+    tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+    }
+}
+
+/// Delete or hand off the sessions still open once the requests in flight
+/// are drained, or aborted, then log the summary of the shutdown.
+pub async fn close_sessions(
+    state: &AppState,
+    signal: &str,
+    drained: bool,
+    action: OpenSessions,
+    handoff_file: &str,
+) {
+    let sessions = state.sessions.open_sessions();
+
+    let closed = match action {
+        OpenSessions::Keep => vec![false; sessions.len()],
+        OpenSessions::Delete => {
+            future::join_all(sessions.iter().map(|session| delete(state, session))).await
+        }
+        OpenSessions::Handoff => {
+            let handed_off = match hand_off(&sessions, Path::new(handoff_file)) {
+                Ok(()) => true,
+                Err(err) => {
+                    error!("The open sessions can't be handed off : {}", err);
+                    false
+                }
+            };
+            vec![handed_off; sessions.len()]
+        }
+    };
+
+    let now = Utc::now();
+    let event = ShutdownEvent {
+        signal: signal.to_string(),
+        drained,
+        aborted_requests: state.metrics.requests_in_flight(),
+        action: action.to_string(),
+        open_sessions: sessions
+            .into_iter()
+            .zip(closed)
+            .map(|(session, closed)| OpenSession {
+                age_secs: (now - session.start_time).num_seconds(),
+                session_id: session.id,
+                owner: session.owner,
+                hub: session.hub,
+                command_count: session.command_count,
+                closed,
+            })
+            .collect(),
+    };
+    let level = match event.drained && event.open_sessions.is_empty() {
+        true => Level::Info,
+        false => Level::Warn,
+    };
+    logging::proxy_event(level, &event);
+}
+
+async fn delete(state: &AppState, session: &Session) -> bool {
+    let deleted = reaper::delete_on_hub(state, session).await;
+    if deleted {
+        if let Some(deleted) = state.sessions.delete(&session.id) {
+            state
+                .metrics
+                .session_changed(SessionStatus::Deleted, &deleted.capabilities);
+        }
+    }
+    deleted
+}
+
+/// Write the open sessions to the hand-off file, for the next proxy.
+fn hand_off(sessions: &[Session], file: &Path) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(sessions).map_err(|err| err.to_string())?;
+    fs::write(file, json).map_err(|err| format!("{} : {}", file.display(), err))
+}
+
+/// Register the sessions handed off by the previous proxy, if any, then
+/// remove the hand-off file so that they are only taken over once.
+pub fn take_over(state: &AppState, handoff_file: &str) -> Result<usize, String> {
+    let file = Path::new(handoff_file);
+    if !file.exists() {
+        return Ok(0);
+    }
+
+    let json = fs::read_to_string(file).map_err(|err| format!("{} : {}", handoff_file, err))?;
+    let sessions: Vec<Session> =
+        serde_json::from_str(&json).map_err(|err| format!("{} : {}", handoff_file, err))?;
+    let count = sessions.len();
+
+    for session in sessions {
+        state.sessions.adopt(session);
+    }
+    fs::remove_file(file).map_err(|err| format!("{} : {}", handoff_file, err))?;
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::DesiredCapabilities;
+    use uuid::Uuid;
+
+    #[test]
+    fn handed_off_sessions_are_taken_over_by_the_next_proxy() {
+        let previous = AppState::new("localhost:4444".to_string(), 60);
+        let request_id = Uuid::new_v4();
+        previous.sessions.queue(
+            request_id,
+            DesiredCapabilities {
+                soda_user: Some("team-a".to_string()),
+                ..DesiredCapabilities::default()
+            },
+        );
+        previous
+            .sessions
+            .activate(request_id, "abc", DesiredCapabilities::default());
+        previous.sessions.set_hub("abc", "hub-2:4444");
+
+        let file = std::env::temp_dir().join(format!("soda-handoff-{}.json", request_id));
+        let file = file.to_str().unwrap();
+        hand_off(&previous.sessions.open_sessions(), Path::new(file)).unwrap();
+
+        let next = AppState::new("localhost:4444".to_string(), 60);
+        assert_eq!(take_over(&next, file), Ok(1));
+
+        let session = next.sessions.get("abc").unwrap();
+        assert_eq!(session.owner, "team-a");
+        assert_eq!(session.hub, Some("hub-2:4444".to_string()));
+        assert_eq!(session.status, SessionStatus::Active);
+        assert!(!Path::new(file).exists());
+        assert_eq!(take_over(&next, file), Ok(0));
+    }
+}
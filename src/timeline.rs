@@ -0,0 +1,324 @@
+use crate::commands::CommandDoneEvent;
+use crate::reverse_proxy::CapturedRequest;
+use chrono::{DateTime, Utc};
+use serde_json::{json, Map, Value};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use uuid::Uuid;
+
+/// Commands kept by session by default, the oldest ones are forgotten.
+pub const DEFAULT_TIMELINE_SIZE: usize = 200;
+
+/// How many sessions keep their timeline, the least recently used ones
+/// are forgotten like the finished sessions of the registry.
+const MAX_TIMELINES: usize = 1000;
+
+/// The parameters with one of these words in their name are never kept.
+const SENSITIVE_PARAMS: &[&str] = &[
+    "password",
+    "passwd",
+    "secret",
+    "token",
+    "authorization",
+    "credential",
+    "apikey",
+    "accesskey",
+];
+
+/// The commands typing text, which may be a password : their text is masked.
+const TYPING_COMMANDS: &[&str] = &[
+    "elementSendKeys",
+    "sendKeysToActiveElement",
+    "sendAlertText",
+    "addCookie",
+    "performActions",
+];
+
+/// Only the beginning of the bodies of the commands is read for the timeline,
+/// the parameters of a bigger body (e.g. an uploaded file) aren't kept.
+pub const MAX_PARAMS_SIZE: usize = 64 * 1024;
+
+/// The longer strings (e.g. a script or an uploaded file) are truncated.
+const MAX_PARAM_LENGTH: usize = 256;
+
+const MASK: &str = "***";
+
+/// A command of the timeline of a session.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TimelineEntry {
+    pub request_id: Uuid,
+    pub command: String,
+    pub method: String,
+    pub path: String,
+    /// The body of the command, with the sensitive values masked. None when
+    /// the body isn't JSON or was too big to be inspected
+    pub params: Option<Value>,
+    pub status: u16,
+    pub error: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: DateTime<Utc>,
+    pub duration_ms: u64,
+    /// The screenshot taken after the error, if any
+    pub screenshot: Option<String>,
+}
+
+impl TimelineEntry {
+    pub fn new(request: &CapturedRequest, done: &CommandDoneEvent) -> TimelineEntry {
+        let ended_at = Utc::now();
+        let params = serde_json::from_slice::<Value>(request.body)
+            .ok()
+            .map(|params| mask(params, TYPING_COMMANDS.contains(&done.command.as_str())));
+
+        TimelineEntry {
+            request_id: request.id,
+            command: done.command.to_owned(),
+            method: request.method.to_string(),
+            path: request.path.to_owned(),
+            params,
+            status: done.status,
+            error: done.error.to_owned(),
+            started_at: ended_at - chrono::Duration::milliseconds(done.duration_ms as i64),
+            ended_at,
+            duration_ms: done.duration_ms,
+            screenshot: done.screenshot.to_owned(),
+        }
+    }
+}
+
+/// Mask the sensitive values of the parameters of a command, the typed text
+/// too when the command types text.
+fn mask(params: Value, is_typing: bool) -> Value {
+    match params {
+        Value::Object(object) => Value::Object(
+            object
+                .into_iter()
+                .map(|(key, value)| {
+                    let name = key.to_lowercase();
+                    let is_sensitive = SENSITIVE_PARAMS.iter().any(|word| name.contains(word))
+                        || (is_typing && (name == "text" || name == "value"));
+                    let value = match is_sensitive {
+                        true => json!(MASK),
+                        false => mask(value, is_typing),
+                    };
+                    (key, value)
+                })
+                .collect::<Map<String, Value>>(),
+        ),
+        Value::Array(values) => Value::Array(
+            values
+                .into_iter()
+                .map(|value| mask(value, is_typing))
+                .collect(),
+        ),
+        Value::String(s) if s.chars().count() > MAX_PARAM_LENGTH => {
+            let truncated: String = s.chars().take(MAX_PARAM_LENGTH).collect();
+            json!(format!("{}... ({} chars)", truncated, s.chars().count()))
+        }
+        other => other,
+    }
+}
+
+struct Timeline {
+    updated: DateTime<Utc>,
+    entries: VecDeque<TimelineEntry>,
+}
+
+/// The last commands of every session, in the order they were done.
+#[derive(Default)]
+pub struct Timelines {
+    timelines: Mutex<HashMap<String, Timeline>>,
+}
+
+impl Timelines {
+    pub fn new() -> Timelines {
+        Timelines::default()
+    }
+
+    /// Add a done command to the timeline of its session, which keeps
+    /// `size` commands at most.
+    pub fn record(&self, session_id: &str, entry: TimelineEntry, size: usize) {
+        if size == 0 {
+            return;
+        }
+
+        let mut timelines = self.timelines.lock().unwrap();
+        let timeline = timelines
+            .entry(session_id.to_string())
+            .or_insert_with(|| Timeline {
+                updated: Utc::now(),
+                entries: VecDeque::new(),
+            });
+        timeline.updated = Utc::now();
+        timeline.entries.push_back(entry);
+        while timeline.entries.len() > size {
+            timeline.entries.pop_front();
+        }
+
+        if timelines.len() > MAX_TIMELINES {
+            let oldest = timelines
+                .iter()
+                .min_by_key(|(_, timeline)| timeline.updated)
+                .map(|(session_id, _)| session_id.to_owned());
+            if let Some(oldest) = oldest {
+                timelines.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn get(&self, session_id: &str) -> Option<Vec<TimelineEntry>> {
+        self.timelines
+            .lock()
+            .unwrap()
+            .get(session_id)
+            .map(|timeline| timeline.entries.iter().cloned().collect())
+    }
+}
+
+/// The timeline in the HAR format (HTTP Archive), to be opened by the tools
+/// reading the network captures. The commands are the entries, their
+/// parameters the posted data and their WebDriver error the comment.
+pub fn to_har(session_id: &str, entries: &[TimelineEntry]) -> Value {
+    let entries: Vec<Value> = entries
+        .iter()
+        .map(|entry| {
+            let mut request = json!({
+                "method": entry.method,
+                "url": entry.path,
+                "httpVersion": "HTTP/1.1",
+                "cookies": [],
+                "headers": [],
+                "queryString": [],
+                "headersSize": -1,
+                "bodySize": -1,
+            });
+            if let Some(params) = &entry.params {
+                request["postData"] = json!({
+                    "mimeType": "application/json",
+                    "text": params.to_string(),
+                });
+            }
+
+            json!({
+                "pageref": session_id,
+                "startedDateTime": entry.started_at,
+                "time": entry.duration_ms,
+                "request": request,
+                "response": {
+                    "status": entry.status,
+                    "statusText": "",
+                    "httpVersion": "HTTP/1.1",
+                    "cookies": [],
+                    "headers": [],
+                    "content": { "size": -1, "mimeType": "application/json" },
+                    "redirectURL": "",
+                    "headersSize": -1,
+                    "bodySize": -1,
+                },
+                "cache": {},
+                "timings": { "send": 0, "wait": entry.duration_ms, "receive": 0 },
+                "comment": match (&entry.error, &entry.screenshot) {
+                    (Some(error), Some(screenshot)) => {
+                        format!("{} : {} (screenshot {})", entry.command, error, screenshot)
+                    }
+                    (Some(error), None) => format!("{} : {}", entry.command, error),
+                    (None, _) => entry.command.to_owned(),
+                },
+            })
+        })
+        .collect();
+
+    json!({
+        "log": {
+            "version": "1.2",
+            "creator": {
+                "name": env!("CARGO_PKG_NAME"),
+                "version": env!("CARGO_PKG_VERSION"),
+            },
+            "pages": [{
+                "id": session_id,
+                "title": format!("Session {}", session_id),
+                "startedDateTime": entries.first().map(|entry| entry["startedDateTime"].clone()),
+                "pageTimings": {},
+            }],
+            "entries": entries,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::CommandEvent;
+    use crate::logging::Outcome;
+    use crate::webdriver::DEFAULT_BASE_PATH;
+    use bytes::Bytes;
+    use hyper::{Method, StatusCode};
+    use std::time::Duration;
+    use url::Url;
+
+    fn entry_of(path: &str, body: &str) -> TimelineEntry {
+        let body = Bytes::from(body.to_string());
+        let request = CapturedRequest {
+            id: Uuid::new_v4(),
+            url: Url::parse(&format!("http://localhost:4444{}", path)).unwrap(),
+            method: &Method::POST,
+            path: path.to_string(),
+            body: &body,
+            user: None,
+        };
+        let outcome = Outcome {
+            status: StatusCode::OK,
+            duration: Duration::from_millis(5),
+        };
+        let done = CommandDoneEvent::new(
+            &CommandEvent::of(&Method::POST, path, DEFAULT_BASE_PATH),
+            &outcome,
+            b"",
+        );
+
+        TimelineEntry::new(&request, &done)
+    }
+
+    #[test]
+    fn timeline_entry_masks_the_sensitive_params() {
+        let login = entry_of(
+            "/session/123/element/456/value",
+            r#"{"text":"hunter2","value":["h","u"]}"#,
+        );
+        assert_eq!(login.command, "elementSendKeys");
+        assert_eq!(login.params, Some(json!({"text": "***", "value": "***"})));
+
+        let script = entry_of(
+            "/session/123/execute/sync",
+            &json!({"script": "x".repeat(300), "args": [{"apiToken": "abc"}]}).to_string(),
+        );
+        assert_eq!(
+            script.params.as_ref().unwrap()["args"][0]["apiToken"],
+            "***"
+        );
+        assert!(script.params.as_ref().unwrap()["script"]
+            .as_str()
+            .unwrap()
+            .ends_with("... (300 chars)"));
+
+        let url = entry_of("/session/123/url", r#"{"url":"https://example.com"}"#);
+        assert_eq!(url.params, Some(json!({"url": "https://example.com"})));
+    }
+
+    #[test]
+    fn timeline_keeps_the_last_commands_of_the_session() {
+        let timelines = Timelines::new();
+        for _ in 0..3 {
+            timelines.record("123", entry_of("/session/123/url", "{}"), 2);
+        }
+        timelines.record("456", entry_of("/session/456/url", "{}"), 0);
+
+        assert_eq!(timelines.get("123").unwrap().len(), 2);
+        assert!(timelines.get("456").is_none());
+
+        let har = to_har("123", &timelines.get("123").unwrap());
+        assert_eq!(har["log"]["entries"].as_array().unwrap().len(), 2);
+        assert_eq!(har["log"]["entries"][0]["comment"], "navigateTo");
+    }
+}
@@ -0,0 +1,170 @@
+use crate::commands::CommandPattern;
+use hyper::Method;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Timeout of the commands matching a pattern, optionally only for a user.
+/// Format : `[USER@]METHOD /PATH=DURATION_IN_SECS`, e.g. `POST /execute/async=300`
+/// or `payment@POST /url=120`
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimeoutRule {
+    pub user: Option<String>,
+    pub command: CommandPattern,
+    pub timeout: Duration,
+}
+
+impl FromStr for TimeoutRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (rule, timeout) = s.rsplit_once('=').ok_or_else(|| {
+            format!(
+                "Timeout rule {} : format must be [USER@]METHOD /PATH=DURATION_IN_SECS",
+                s
+            )
+        })?;
+        let timeout = timeout.trim().parse::<u64>().map_err(|_| {
+            format!(
+                "Timeout rule {} : the timeout must be a number of seconds",
+                s
+            )
+        })?;
+
+        let (user, command) = match rule.split_once('@') {
+            Some((user, command)) if !user.trim().is_empty() && !user.contains(' ') => {
+                (Some(user.trim().to_string()), command)
+            }
+            _ => (None, rule),
+        };
+
+        Ok(TimeoutRule {
+            user,
+            command: command
+                .parse()
+                .map_err(|err| format!("Timeout rule {} : {}", s, err))?,
+            timeout: Duration::from_secs(timeout),
+        })
+    }
+}
+
+impl fmt::Display for TimeoutRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(user) = &self.user {
+            write!(f, "{}@", user)?;
+        }
+        write!(f, "{}={}", self.command, self.timeout.as_secs())
+    }
+}
+
+/// The timeout given to a request, and the rule which gave it.
+pub struct AppliedTimeout<'a> {
+    pub duration: Duration,
+    pub rule: Option<&'a TimeoutRule>,
+}
+
+impl fmt::Display for AppliedTimeout<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.rule {
+            Some(rule) => write!(f, "{}s (rule {})", self.duration.as_secs(), rule),
+            None => write!(f, "{}s (--timeout)", self.duration.as_secs()),
+        }
+    }
+}
+
+/// The timeouts of the commands : the first rule matching a command, or the
+/// default timeout when no rule matches.
+pub struct Timeouts {
+    default: Duration,
+    rules: Vec<TimeoutRule>,
+}
+
+impl Timeouts {
+    pub fn new(default: Duration, rules: Vec<TimeoutRule>) -> Timeouts {
+        Timeouts { default, rules }
+    }
+
+    /// Timeout of a command of a session owned by `owner`.
+    pub fn timeout_for(
+        &self,
+        method: &Method,
+        path: &str,
+        base_path: &str,
+        owner: Option<&str>,
+    ) -> AppliedTimeout<'_> {
+        let rule = self.rules.iter().find(|rule| {
+            let is_for_the_owner = match &rule.user {
+                Some(user) => Some(user.as_str()) == owner,
+                None => true,
+            };
+            is_for_the_owner && rule.command.matches(method, path, base_path)
+        });
+
+        AppliedTimeout {
+            duration: rule.map(|rule| rule.timeout).unwrap_or(self.default),
+            rule,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webdriver::DEFAULT_BASE_PATH;
+
+    #[test]
+    fn timeout_rule_is_parsed_with_an_optional_user() {
+        let rule: TimeoutRule = "POST /execute/async=300".parse().unwrap();
+        assert_eq!(rule.user, None);
+        assert_eq!(rule.timeout, Duration::from_secs(300));
+        assert_eq!(rule.to_string(), "POST /execute/async=300");
+
+        let rule: TimeoutRule = "payment@POST /url=120".parse().unwrap();
+        assert_eq!(rule.user, Some("payment".to_string()));
+        assert_eq!(rule.to_string(), "payment@POST /url=120");
+
+        assert!("POST /url".parse::<TimeoutRule>().is_err());
+        assert!("POST /url=two".parse::<TimeoutRule>().is_err());
+        assert!("url=120".parse::<TimeoutRule>().is_err());
+    }
+
+    #[test]
+    fn timeout_for_picks_the_first_rule_matching_the_command_and_the_user() {
+        let timeouts = Timeouts::new(
+            Duration::from_secs(60),
+            vec![
+                "payment@POST /url=120".parse().unwrap(),
+                "POST /execute/async=300".parse().unwrap(),
+            ],
+        );
+
+        let async_script = timeouts.timeout_for(
+            &Method::POST,
+            "/wd/hub/session/123/execute/async",
+            DEFAULT_BASE_PATH,
+            Some("search"),
+        );
+        assert_eq!(async_script.duration, Duration::from_secs(300));
+        assert_eq!(
+            async_script.to_string(),
+            "300s (rule POST /execute/async=300)"
+        );
+
+        let payment_url = timeouts.timeout_for(
+            &Method::POST,
+            "/wd/hub/session/123/url",
+            DEFAULT_BASE_PATH,
+            Some("payment"),
+        );
+        assert_eq!(payment_url.duration, Duration::from_secs(120));
+
+        let search_url = timeouts.timeout_for(
+            &Method::POST,
+            "/wd/hub/session/123/url",
+            DEFAULT_BASE_PATH,
+            Some("search"),
+        );
+        assert_eq!(search_url.duration, Duration::from_secs(60));
+        assert_eq!(search_url.to_string(), "60s (--timeout)");
+    }
+}
@@ -0,0 +1,224 @@
+use hyper::{header, Body, Method, Response, StatusCode};
+use serde_json::json;
+use std::str::FromStr;
+
+/// The prefixes of the WebDriver endpoints always recognised : the one of
+/// Selenium 3, and none for Selenium 4 and the W3C clients.
+const DEFAULT_BASE_PATHS: &[&str] = &["/wd/hub", ""];
+
+/// The prefix of the WebDriver endpoints of the hubs, without `--base-path`.
+pub const DEFAULT_BASE_PATH: &str = "/wd/hub";
+
+/// WebDriver error code of a refused new session request.
+pub const SESSION_NOT_CREATED: &str = "session not created";
+
+/// WebDriver error code of an invalid request.
+pub const INVALID_ARGUMENT: &str = "invalid argument";
+
+/// WebDriver error code of an operation which didn't complete in time.
+pub const TIMEOUT: &str = "timeout";
+
+/// WebDriver error code of the errors without a more specific code.
+pub const UNKNOWN_ERROR: &str = "unknown error";
+
+/// Build an error response in the W3C WebDriver format, for the requests
+/// answered by the proxy itself instead of the hub, so that the Selenium
+/// clients raise their usual exception with our message.
+pub fn error_response(status: StatusCode, error: &str, message: &str) -> Response<Body> {
+    let body = json!({
+        "value": {
+            "error": error,
+            "message": message,
+            "stacktrace": "",
+        }
+    });
+
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json; charset=utf-8")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+/// A prefix of the WebDriver endpoints, e.g. `/selenium` for
+/// `/selenium/session`, or `/` for none.
+#[derive(Debug, PartialEq)]
+pub struct BasePath(pub String);
+
+impl FromStr for BasePath {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !s.starts_with('/') {
+            return Err(format!("The base path {} must start with /", s));
+        }
+        Ok(BasePath(s.trim_end_matches('/').to_string()))
+    }
+}
+
+/// A WebDriver endpoint, recognised from the method and the path of a request.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Route {
+    NewSession,
+    DeleteSession {
+        session_id: String,
+    },
+    /// Any other command of a session, `command` is the path after the
+    /// session id, e.g. `/element/456/click` (empty to get the session)
+    SessionCommand {
+        session_id: String,
+        command: String,
+    },
+    Status,
+    Other,
+}
+
+impl Route {
+    /// The route of a request, under the base path or the default prefixes.
+    /// The query is ignored.
+    pub fn of(method: &Method, path: &str, base_path: &str) -> Route {
+        let path = path.split('?').next().unwrap_or_default();
+
+        for base_path in std::iter::once(&base_path).chain(DEFAULT_BASE_PATHS) {
+            let endpoint = match path.strip_prefix(base_path) {
+                Some(endpoint) => endpoint,
+                None => continue,
+            };
+            if endpoint.trim_end_matches('/') == "/status" {
+                return Route::Status;
+            }
+            if let Some(session) = endpoint.strip_prefix("/session") {
+                return Route::of_session(method, session);
+            }
+        }
+
+        Route::Other
+    }
+
+    /// The route of the path following `/session`.
+    fn of_session(method: &Method, session: &str) -> Route {
+        if session.is_empty() || session == "/" {
+            return match *method {
+                Method::POST => Route::NewSession,
+                _ => Route::Other,
+            };
+        }
+
+        // The session id must directly follow `/session/`
+        let (session_id, command) = match session.strip_prefix('/') {
+            Some(session) => session.split_at(session.find('/').unwrap_or(session.len())),
+            None => return Route::Other,
+        };
+        if session_id.is_empty() {
+            return Route::Other;
+        }
+
+        let command = command.trim_end_matches('/');
+        match (method, command) {
+            (&Method::DELETE, "") => Route::DeleteSession {
+                session_id: session_id.to_string(),
+            },
+            _ => Route::SessionCommand {
+                session_id: session_id.to_string(),
+                command: command.to_string(),
+            },
+        }
+    }
+
+    pub fn session_id(&self) -> Option<&str> {
+        match self {
+            Route::DeleteSession { session_id } | Route::SessionCommand { session_id, .. } => {
+                Some(session_id)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[tokio::test]
+    async fn error_response_follows_the_webdriver_format() {
+        let response = error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            SESSION_NOT_CREATED,
+            "quota reached",
+        );
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["value"]["error"], "session not created");
+        assert_eq!(body["value"]["message"], "quota reached");
+    }
+
+    #[test]
+    fn route_is_recognised_with_or_without_the_wd_hub_prefix() {
+        for prefix in &["/wd/hub", ""] {
+            let route = |method: Method, path: &str| {
+                Route::of(&method, &format!("{}{}", prefix, path), DEFAULT_BASE_PATH)
+            };
+
+            assert_eq!(route(Method::POST, "/session"), Route::NewSession);
+            assert_eq!(route(Method::POST, "/session/"), Route::NewSession);
+            assert_eq!(
+                route(Method::DELETE, "/session/123"),
+                Route::DeleteSession {
+                    session_id: "123".to_string()
+                }
+            );
+            assert_eq!(
+                route(Method::POST, "/session/123/element/456/click?x=1"),
+                Route::SessionCommand {
+                    session_id: "123".to_string(),
+                    command: "/element/456/click".to_string()
+                }
+            );
+            assert_eq!(
+                route(Method::DELETE, "/session/123/cookie"),
+                Route::SessionCommand {
+                    session_id: "123".to_string(),
+                    command: "/cookie".to_string()
+                }
+            );
+            assert_eq!(route(Method::GET, "/status"), Route::Status);
+            assert_eq!(route(Method::GET, "/session"), Route::Other);
+            assert_eq!(route(Method::GET, "/session//screenshot"), Route::Other);
+        }
+
+        assert_eq!(
+            Route::of(&Method::POST, "/bad/hub/session", DEFAULT_BASE_PATH),
+            Route::Other
+        );
+        assert_eq!(
+            Route::of(&Method::POST, "/sessions", DEFAULT_BASE_PATH),
+            Route::Other
+        );
+    }
+
+    #[test]
+    fn route_is_recognised_under_the_base_path() {
+        assert_eq!(
+            Route::of(&Method::POST, "/selenium/session", "/selenium"),
+            Route::NewSession
+        );
+        assert_eq!(
+            Route::of(&Method::GET, "/selenium/session/123/url", "/selenium").session_id(),
+            Some("123")
+        );
+        assert_eq!(
+            Route::of(&Method::POST, "/wd/hub/session", "/selenium"),
+            Route::NewSession
+        );
+        assert_eq!(
+            Route::of(&Method::POST, "/selenium/session", DEFAULT_BASE_PATH),
+            Route::Other
+        );
+        assert_eq!("/selenium/".parse(), Ok(BasePath("/selenium".to_string())));
+        assert_eq!("/".parse(), Ok(BasePath("".to_string())));
+        assert!("selenium".parse::<BasePath>().is_err());
+    }
+}